@@ -1,4 +1,12 @@
 //Google does not work since Google recognizes that the browser is controlled through a WebDriver
+const fs = require('fs');
+
+// Write the stage reached and the reason of a failure to the file PassUp reads after the run
+function report(stage, reason) {
+	if (process.env.PASSUP_RESULT) {
+		fs.writeFileSync(process.env.PASSUP_RESULT, JSON.stringify({ stage: stage, reason: reason }));
+	}
+}
 
 module.exports = {
 'google.com' : function(browser) {
@@ -24,6 +32,7 @@ module.exports = {
       	.setValue('input[name=password]', oldPasswd)
 		.click('button[type=button]')
 		.waitForElementPresent('div.GWwaOc')
+		.perform(() => report('logged_in'))
 		.click('a.GWwaOc')
 		.waitForElementPresent('a.VZLjze')
 		.click('a.VZLjze')
@@ -34,7 +43,9 @@ module.exports = {
       	.setValue('input[name=password]', newPasswd)
 		.setValue('input[name=confirmation_password]', newPasswd)
 		.click('button[type=button]')
+		.perform(() => report('submitted'))
 		.waitForElementPresent('a.VZLjze')
+		.perform(() => report('confirmed'))
 		.end();
   }
 };
@@ -0,0 +1,634 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io, result};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt, encrypt, Cipher};
+use rand::RngCore;
+use rpassword::read_password;
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{Configuration, Source};
+use crate::utils::{
+    self, commit_results, get_pw, run_update_threads, write_private_file, DBEntry, Uuid, DB,
+};
+
+const LOGIN_ITEM_TYPE: u64 = 1;
+const KDF_PBKDF2: u64 = 0;
+const KDF_ARGON2ID: u64 = 1;
+const ENC_TYPE_AES256_CBC_HMAC: &str = "2";
+const UPDATED_SUFFIX: &str = "updated";
+const DIFF_SUFFIX: &str = "diff";
+
+#[derive(Debug, Snafu)]
+enum LibraryError {
+    IoError { source: io::Error },
+    OpensslError { source: openssl::error::ErrorStack },
+    Argon2Error { source: argon2::Error },
+    JsonError { source: serde_json::Error },
+    Base64Error { source: base64::DecodeError },
+    UtilsError { source: utils::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("Could not open \'{}\': {}", file, source))]
+    OpenFailed {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("\'{}\' is not a valid Bitwarden export", file))]
+    ExportWrongFormat {
+        file: String,
+    },
+    #[snafu(display(
+        "\'{}\' is encrypted with the account key, export it with a password instead",
+        file
+    ))]
+    AccountEncrypted {
+        file: String,
+    },
+    #[snafu(display("KDF type {} is not supported", kdf_type))]
+    UnsupportedKdf {
+        kdf_type: u64,
+    },
+    #[snafu(display("Encrypted value is not a valid Bitwarden cipher string"))]
+    EncStringError,
+    #[snafu(display("MAC of encrypted value does not match"))]
+    MacMismatch,
+    #[snafu(display("Could not derive key: {}", source))]
+    KeyDerivationError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not decrypt value: {}", source))]
+    DecryptError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not encrypt value: {}", source))]
+    EncryptError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not write \'{}\': {}", file, source))]
+    WriteFailed {
+        file: String,
+        source: LibraryError,
+    },
+    LibError {
+        source: LibraryError,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// Key of a password protected export, stretched into encryption and MAC key.
+struct ExportKey {
+    enc_key: Vec<u8>,
+    mac_key: Vec<u8>,
+}
+
+/// The parsed export. For password protected exports `envelope` holds the
+/// outer JSON with the KDF parameters and `key` the key derived from them.
+struct Export {
+    file: PathBuf,
+    vault: Value,
+    envelope: Option<Value>,
+    key: Option<ExportKey>,
+}
+
+pub fn run(config: &Configuration) {
+    for source in &config.sources {
+        let mut export = match open_export(source) {
+            Ok(export) => export,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+        let db = match parse_items(&export) {
+            Ok(db) => db,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+
+        let (tx, rx) = channel();
+        run_update_threads(&db, &source.blocklist, config, tx);
+
+        let mut updated_items = Vec::new();
+        commit_results(rx, false, |updated_db| {
+            write_exports(&mut export, &mut updated_items, updated_db)
+        });
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Result<Vec<u8>, LibraryError> {
+    let pkey = PKey::hmac(key).context(OpensslError)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &pkey).context(OpensslError)?;
+    signer.update(data).context(OpensslError)?;
+    signer.sign_to_vec().context(OpensslError)
+}
+
+/// Derive the export key like Bitwarden's PIN key: the KDF output is
+/// stretched with HKDF-Expand into a 32 byte encryption and a 32 byte MAC key.
+fn derive_key(envelope: &Value, password: &str) -> Result<ExportKey> {
+    let field = |name: &str| envelope.get(name).map(|v| v.as_u64()).flatten();
+    let salt = envelope
+        .get("salt")
+        .map(|v| v.as_str())
+        .flatten()
+        .ok_or(Error::EncStringError)?;
+    let kdf_type = field("kdfType").unwrap_or(KDF_PBKDF2);
+    let iterations = field("kdfIterations").ok_or(Error::EncStringError)? as u32;
+
+    let mut key = [0u8; 32];
+    if kdf_type == KDF_PBKDF2 {
+        pbkdf2_hmac(
+            password.as_bytes(),
+            salt.as_bytes(),
+            iterations as usize,
+            MessageDigest::sha256(),
+            &mut key,
+        )
+        .context(OpensslError)
+        .context(KeyDerivationError)?;
+    } else if kdf_type == KDF_ARGON2ID {
+        let memory = field("kdfMemory").ok_or(Error::EncStringError)? as u32;
+        let parallelism = field("kdfParallelism").ok_or(Error::EncStringError)? as u32;
+        let salt_hash = hash(MessageDigest::sha256(), salt.as_bytes())
+            .context(OpensslError)
+            .context(KeyDerivationError)?;
+        let params = Params::new(memory * 1024, iterations, parallelism, Some(key.len()))
+            .context(Argon2Error)
+            .context(KeyDerivationError)?;
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(password.as_bytes(), &salt_hash, &mut key)
+            .context(Argon2Error)
+            .context(KeyDerivationError)?;
+    } else {
+        return Err(Error::UnsupportedKdf { kdf_type });
+    }
+
+    // HKDF-Expand with a single block, the output length equals the hash length
+    let enc_key = hmac_sha256(&key, b"enc\x01").context(KeyDerivationError)?;
+    let mac_key = hmac_sha256(&key, b"mac\x01").context(KeyDerivationError)?;
+    Ok(ExportKey { enc_key, mac_key })
+}
+
+/// Decrypt a cipher string of the form `2.<iv>|<ciphertext>|<mac>`.
+fn decrypt_enc_string(key: &ExportKey, enc_string: &str) -> Result<Vec<u8>> {
+    let (enc_type, data) = enc_string.split_once('.').ok_or(Error::EncStringError)?;
+    if enc_type != ENC_TYPE_AES256_CBC_HMAC {
+        return Err(Error::EncStringError);
+    }
+    let parts = data
+        .split('|')
+        .map(base64::decode)
+        .collect::<result::Result<Vec<_>, _>>()
+        .context(Base64Error)
+        .context(DecryptError)?;
+    if parts.len() != 3 {
+        return Err(Error::EncStringError);
+    }
+    let (iv, ciphertext, mac) = (&parts[0], &parts[1], &parts[2]);
+
+    let expected_mac =
+        hmac_sha256(&key.mac_key, &[iv.as_slice(), ciphertext].concat()).context(DecryptError)?;
+    if !openssl::memcmp::eq(&expected_mac, mac) {
+        return Err(Error::MacMismatch);
+    }
+    decrypt(Cipher::aes_256_cbc(), &key.enc_key, Some(iv), ciphertext)
+        .context(OpensslError)
+        .context(DecryptError)
+}
+
+fn encrypt_enc_string(key: &ExportKey, data: &[u8]) -> Result<String> {
+    let mut iv = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut iv);
+    let ciphertext = encrypt(Cipher::aes_256_cbc(), &key.enc_key, Some(&iv), data)
+        .context(OpensslError)
+        .context(EncryptError)?;
+    let mac = hmac_sha256(&key.mac_key, &[&iv[..], &ciphertext].concat()).context(EncryptError)?;
+    Ok(format!(
+        "{}.{}|{}|{}",
+        ENC_TYPE_AES256_CBC_HMAC,
+        base64::encode(iv),
+        base64::encode(ciphertext),
+        base64::encode(mac)
+    ))
+}
+
+fn unlock_export(source: &Source, envelope: &Value) -> Result<(Value, ExportKey)> {
+    let data =
+        envelope
+            .get("data")
+            .map(|v| v.as_str())
+            .flatten()
+            .ok_or(Error::ExportWrongFormat {
+                file: source.file.to_owned(),
+            })?;
+
+    println!(
+        "Please enter the export password for {} at {}",
+        source.name, source.file
+    );
+    loop {
+        let password = read_password().unwrap_or_else(|_| "".to_owned());
+        let key = derive_key(envelope, &password)?;
+        match decrypt_enc_string(&key, data) {
+            Ok(plaintext) => {
+                let vault = serde_json::from_slice(&plaintext)
+                    .context(JsonError)
+                    .context(OpenFailed {
+                        file: source.file.to_owned(),
+                    })?;
+                return Ok((vault, key));
+            }
+            Err(Error::MacMismatch) => println!("Wrong password! Please try again:"),
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+fn open_export(source: &Source) -> Result<Export> {
+    let content = fs::read(&source.file)
+        .context(IoError)
+        .context(OpenFailed {
+            file: source.file.to_owned(),
+        })?;
+    let json: Value = serde_json::from_slice(&content)
+        .context(JsonError)
+        .context(OpenFailed {
+            file: source.file.to_owned(),
+        })?;
+
+    let encrypted = json.get("encrypted").map(|v| v.as_bool()).flatten();
+    let password_protected = json.get("passwordProtected").map(|v| v.as_bool()).flatten();
+    let file = PathBuf::from(&source.file);
+    match (encrypted, password_protected) {
+        (Some(true), Some(true)) => {
+            let (vault, key) = unlock_export(source, &json)?;
+            Ok(Export {
+                file,
+                vault,
+                envelope: Some(json),
+                key: Some(key),
+            })
+        }
+        (Some(true), _) => Err(Error::AccountEncrypted {
+            file: source.file.to_owned(),
+        }),
+        _ => Ok(Export {
+            file,
+            vault: json,
+            envelope: None,
+            key: None,
+        }),
+    }
+}
+
+fn item_uris(login: &Value) -> Vec<String> {
+    login
+        .get("uris")
+        .map(|u| u.as_array())
+        .flatten()
+        .map(|uris| {
+            uris.iter()
+                .filter_map(|uri| uri.get("uri").map(|u| u.as_str()).flatten())
+                .map(|uri| uri.to_owned())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_items(export: &Export) -> Result<DB> {
+    let items = export
+        .vault
+        .get("items")
+        .map(|i| i.as_array())
+        .flatten()
+        .ok_or(Error::ExportWrongFormat {
+            file: export.file.to_string_lossy().into_owned(),
+        })?;
+
+    let mut db_vec = Vec::new();
+    for item in items {
+        if item.get("type").map(|t| t.as_u64()).flatten() != Some(LOGIN_ITEM_TYPE) {
+            continue;
+        }
+        let id = item.get("id").map(|v| v.as_str()).flatten();
+        let login = match (id, item.get("login")) {
+            (Some(_), Some(login)) => login,
+            _ => continue,
+        };
+        let field = |name: &str| login.get(name).map(|v| v.as_str()).flatten();
+        let (username, password) = match (field("username"), field("password")) {
+            (Some(username), Some(password)) if !username.is_empty() && !password.is_empty() => {
+                (username, password)
+            }
+            _ => continue,
+        };
+        let mut uris = item_uris(login).into_iter();
+        let url = match uris.next() {
+            Some(url) => url,
+            None => continue,
+        };
+
+        let mut db_entry = DBEntry::new(
+            url,
+            username.to_owned(),
+            password.to_owned(),
+            get_pw().context(UtilsError).context(LibError)?,
+        );
+        db_entry.alt_urls = uris.collect();
+        db_entry.uuid = Uuid::Bitwarden(id.unwrap_or_default().to_owned());
+        db_vec.push(db_entry);
+    }
+
+    Ok(DB::new(db_vec))
+}
+
+/// Format the current time like Bitwarden does, e.g. `2021-05-04T13:37:00.000Z`.
+fn timestamp_now() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let days = (secs / 86400) as i64;
+    let time = secs % 86400;
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
+
+/// Set the new passwords and move the old ones into the password history.
+/// Returns the updated items.
+fn update_items(vault: &mut Value, db: &DB) -> Vec<Value> {
+    let now = timestamp_now();
+    let mut updated_items = Vec::new();
+    let items = match vault.get_mut("items").map(|i| i.as_array_mut()).flatten() {
+        Some(items) => items,
+        None => return updated_items,
+    };
+    for item in items.iter_mut() {
+        let id = match item.get("id").map(|v| v.as_str()).flatten() {
+            Some(id) => Uuid::Bitwarden(id.to_owned()),
+            None => continue,
+        };
+        let db_entry = match db.entries.iter().find(|e| e.uuid == id) {
+            Some(db_entry) => db_entry,
+            None => continue,
+        };
+
+        item["login"]["password"] = Value::String(db_entry.new_password.to_owned());
+        item["login"]["passwordRevisionDate"] = Value::String(now.to_owned());
+        item["revisionDate"] = Value::String(now.to_owned());
+        let history = json!({
+            "lastUsedDate": now,
+            "password": db_entry.old_password,
+        });
+        match item
+            .get_mut("passwordHistory")
+            .map(|h| h.as_array_mut())
+            .flatten()
+        {
+            Some(password_history) => password_history.insert(0, history),
+            None => item["passwordHistory"] = Value::Array(vec![history]),
+        }
+        updated_items.push(item.clone());
+    }
+    updated_items
+}
+
+/// Path next to the export, e.g. `bitwarden_export.updated.json`.
+fn sibling_path(file: &Path, suffix: &str) -> PathBuf {
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file.with_file_name(format!("{}.{}.json", stem, suffix))
+}
+
+/// Serialize a vault, encrypting it again if the original export was password
+/// protected. The derived key and KDF parameters are reused.
+fn serialize_vault(export: &Export, vault: &Value) -> Result<Vec<u8>> {
+    let file = export.file.to_string_lossy().into_owned();
+    let content = serde_json::to_vec_pretty(vault)
+        .context(JsonError)
+        .context(WriteFailed {
+            file: file.to_owned(),
+        })?;
+    match (&export.envelope, &export.key) {
+        (Some(envelope), Some(key)) => {
+            let mut envelope = envelope.clone();
+            envelope["data"] = Value::String(encrypt_enc_string(key, &content)?);
+            serde_json::to_vec_pretty(&envelope)
+                .context(JsonError)
+                .context(WriteFailed { file })
+        }
+        _ => Ok(content),
+    }
+}
+
+/// The exports contain the plaintext passwords, so they are only readable by
+/// the user. A new file is written next to the export and renamed over it,
+/// which also replaces the permissions of an existing file.
+fn write_file(file: &Path, content: &[u8]) -> Result<()> {
+    write_private_file(file, content)
+        .context(IoError)
+        .context(WriteFailed {
+            file: file.to_string_lossy().into_owned(),
+        })?;
+    println!("Wrote {}", file.to_string_lossy());
+    Ok(())
+}
+
+/// Write the whole updated export and a diff export which only contains the
+/// items updated so far and their folders, the original export is kept.
+fn write_exports(
+    export: &mut Export,
+    updated_items: &mut Vec<Value>,
+    db: &DB,
+) -> Result<Vec<Uuid>> {
+    if db.entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    let items = update_items(&mut export.vault, db);
+    let written = items
+        .iter()
+        .filter_map(|item| item.get("id").map(|id| id.as_str()).flatten())
+        .map(|id| Uuid::Bitwarden(id.to_owned()))
+        .collect();
+    updated_items.extend(items);
+
+    let mut diff = export.vault.clone();
+    let folder_ids = updated_items
+        .iter()
+        .filter_map(|item| item.get("folderId").map(|f| f.as_str()).flatten())
+        .collect::<Vec<_>>();
+    if let Some(folders) = diff.get_mut("folders").map(|f| f.as_array_mut()).flatten() {
+        folders.retain(|folder| {
+            folder
+                .get("id")
+                .map(|id| id.as_str())
+                .flatten()
+                .map(|id| folder_ids.contains(&id))
+                .unwrap_or(false)
+        });
+    }
+    diff["items"] = Value::Array(updated_items.to_owned());
+
+    let updated_content = serialize_vault(export, &export.vault)?;
+    let diff_content = serialize_vault(export, &diff)?;
+    write_file(
+        &sibling_path(&export.file, UPDATED_SUFFIX),
+        &updated_content,
+    )?;
+    write_file(&sibling_path(&export.file, DIFF_SUFFIX), &diff_content)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::PRIVATE_FILE_MODE;
+
+    const SALT: &str = "7a3c2b1d-salt";
+    const PASSWORD: &str = "export password";
+    /// `{"items":[]}` encrypted with the PBKDF2 key below
+    const ENC_STRING: &str = "2.AAECAwQFBgcICQoLDA0ODw==|sCVavmFU+KRFKEJvQCiPlw==|\
+                              ScJt0bCR2Qi32UvRZ3zWj4JEWnP54cKSB0PZ70HzCCs=";
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn pbkdf2_key() -> ExportKey {
+        let envelope = json!({"salt": SALT, "kdfType": 0, "kdfIterations": 5000});
+        derive_key(&envelope, PASSWORD).unwrap()
+    }
+
+    #[test]
+    fn derive_key_pbkdf2() {
+        let key = pbkdf2_key();
+        assert_eq!(
+            key.enc_key,
+            unhex("5e273fa1da9ff6ae930b1d30818000462d93d3354635eec52fd5794f29fe41e6")
+        );
+        assert_eq!(
+            key.mac_key,
+            unhex("acd3da0c86118f0a2ee448355780b45eca0815e2aaf324b4630aafe3ec320598")
+        );
+    }
+
+    #[test]
+    fn derive_key_argon2id() {
+        let envelope = json!({
+            "salt": SALT,
+            "kdfType": 1,
+            "kdfIterations": 3,
+            "kdfMemory": 16,
+            "kdfParallelism": 4,
+        });
+        let key = derive_key(&envelope, PASSWORD).unwrap();
+        assert_eq!(
+            key.enc_key,
+            unhex("ccd87bce257644708229fef7a8c0ab85c13e3107eaf8f034a76d95c1f02c9bea")
+        );
+        assert_eq!(
+            key.mac_key,
+            unhex("9274e7e50a85a3c8322b709c4ad0b30e1cbbd390c86bb607f7a2e9b5ebd77de6")
+        );
+    }
+
+    #[test]
+    fn derive_key_rejects_unknown_kdf() {
+        let envelope = json!({"salt": SALT, "kdfType": 7, "kdfIterations": 1});
+        assert!(matches!(
+            derive_key(&envelope, PASSWORD),
+            Err(Error::UnsupportedKdf { kdf_type: 7 })
+        ));
+    }
+
+    #[test]
+    fn decrypt_enc_string_known_value() {
+        let plaintext = decrypt_enc_string(&pbkdf2_key(), ENC_STRING).unwrap();
+        assert_eq!(plaintext, br#"{"items":[]}"#);
+    }
+
+    #[test]
+    fn decrypt_enc_string_checks_mac_and_type() {
+        let key = pbkdf2_key();
+        let wrong_key = derive_key(
+            &json!({"salt": SALT, "kdfType": 0, "kdfIterations": 5000}),
+            "wrong",
+        )
+        .unwrap();
+        assert!(matches!(
+            decrypt_enc_string(&wrong_key, ENC_STRING),
+            Err(Error::MacMismatch)
+        ));
+        assert!(matches!(
+            decrypt_enc_string(&key, &ENC_STRING.replacen("2.", "0.", 1)),
+            Err(Error::EncStringError)
+        ));
+        assert!(matches!(
+            decrypt_enc_string(&key, "2.AAAA|AAAA"),
+            Err(Error::EncStringError)
+        ));
+    }
+
+    #[test]
+    fn write_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let file = std::env::temp_dir().join(format!("passup-bw-test-{}.json", std::process::id()));
+        fs::write(&file, b"old").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        write_file(&file, b"new").unwrap();
+        let mode = fs::metadata(&file).unwrap().permissions().mode();
+        let content = fs::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(mode & 0o777, PRIVATE_FILE_MODE);
+        assert_eq!(content, b"new");
+    }
+
+    #[test]
+    fn enc_string_roundtrip() {
+        let key = pbkdf2_key();
+        let enc_string = encrypt_enc_string(&key, b"new password").unwrap();
+        assert!(enc_string.starts_with("2."));
+        assert_eq!(
+            decrypt_enc_string(&key, &enc_string).unwrap(),
+            b"new password"
+        );
+    }
+}
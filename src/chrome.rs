@@ -1,14 +1,70 @@
+use std::path::Path;
 use std::sync::mpsc::channel;
-use std::{io, result, str};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::{fs, io, result, str, thread};
 
 use libaes::Cipher;
 use openssl::{hash, pkcs5::pbkdf2_hmac};
-use rusqlite::{params, Connection};
+use rusqlite::{params, Connection, OptionalExtension};
 use snafu::{ResultExt, Snafu};
 
 use crate::config::{Configuration, ProfileTypes, Source};
 use crate::keyring;
-use crate::utils::{self, get_pw, run_update_threads, DBEntry, DB};
+use crate::utils::{
+    self, commit_results, get_pw, run_update_threads, ChromeLoginId, DBEntry, Uuid, DB,
+};
+
+const LOGIN_DB_FILES: [&str; 2] = ["Login Data", "Login Data For Account"];
+const SINGLETON_LOCK: &str = "SingletonLock";
+const BROWSER_WAIT: Duration = Duration::from_secs(60);
+const BROWSER_POLL: Duration = Duration::from_secs(2);
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Seconds between 1601-01-01, the epoch of Chrome timestamps, and 1970-01-01.
+const CHROME_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+/// A Chromium based browser, its directory below `~/.config` and the
+/// application name its Safe Storage secret is saved under. KWallet stores
+/// the secret as "<name> Safe Storage" in the folder "<name> Keys".
+#[derive(Debug)]
+struct ChromiumBrowser {
+    name: &'static str,
+    config_dir: &'static str,
+    application: &'static str,
+}
+
+const BROWSERS: [ChromiumBrowser; 5] = [
+    ChromiumBrowser {
+        name: "Chrome",
+        config_dir: "google-chrome",
+        application: "chrome",
+    },
+    ChromiumBrowser {
+        name: "Chromium",
+        config_dir: "chromium",
+        application: "chromium",
+    },
+    ChromiumBrowser {
+        name: "Brave",
+        config_dir: "BraveSoftware/Brave-Browser",
+        application: "brave",
+    },
+    ChromiumBrowser {
+        name: "Vivaldi",
+        config_dir: "vivaldi",
+        application: "vivaldi",
+    },
+    ChromiumBrowser {
+        name: "Microsoft Edge",
+        config_dir: "microsoft-edge",
+        application: "microsoft-edge",
+    },
+];
+
+#[derive(Debug)]
+struct LoginDb {
+    file: String,
+    browser: &'static ChromiumBrowser,
+}
 
 #[derive(Debug, Snafu)]
 enum LibraryError {
@@ -50,92 +106,222 @@ enum Error {
     LibError {
         source: LibraryError,
     },
+    #[snafu(display("{} is still running, please close it to update \'{}\'", browser, file))]
+    BrowserRunning {
+        browser: String,
+        file: String,
+    },
+    #[snafu(display("Entry has wrong uuid type"))]
+    WrongUuidType,
 }
 
 type Result<T, E = Error> = result::Result<T, E>;
 
 #[derive(Debug)]
 struct Login {
-    origin_url: String,
-    username: String,
+    id: ChromeLoginId,
+    action_url: String,
     password: Vec<u8>,
 }
 
 pub fn run(config: &Configuration) {
     for source in &config.sources {
-        let (db, version) = match unlock_and_parse_db(&config.profile.ptype, source) {
-            Ok(val) => val,
-            Err(err) => {
-                println!("Error: {}", err);
-                continue;
-            }
-        };
+        let login_dbs = get_login_dbs(source);
+        if login_dbs.is_empty() {
+            eprintln!("Error: No Chrome login database found for {}", source.name);
+        }
+        for login_db in login_dbs {
+            update_login_db(config, source, &login_db);
+        }
+    }
+}
+
+fn update_login_db(config: &Configuration, source: &Source, login_db: &LoginDb) {
+    if let Err(err) = wait_for_browser_exit(login_db) {
+        eprintln!("Error: {}", err);
+        return;
+    }
+    let (db, version) = match unlock_and_parse_db(&config.profile.ptype, login_db) {
+        Ok(val) => val,
+        Err(err) => {
+            println!("Error: {}", err);
+            return;
+        }
+    };
+
+    let (tx, rx) = channel();
+    run_update_threads(&db, &source.blocklist, config, tx);
+
+    commit_results(rx, false, |updated_db| {
+        update_db(&config.profile.ptype, login_db, updated_db, version.clone())
+    });
+}
 
-        let (tx, rx) = channel();
-        let nr_jobs = run_update_threads(&db, &source.blocklist, config, tx);
+/// Use the configured login database or, if no file is set, every profile of
+/// all installed Chromium based browsers.
+fn get_login_dbs(source: &Source) -> Vec<LoginDb> {
+    if !source.file.is_empty() {
+        let browser = BROWSERS
+            .iter()
+            .find(|b| source.file.contains(&format!("/{}/", b.config_dir)))
+            .unwrap_or(&BROWSERS[0]);
+        return vec![LoginDb {
+            file: source.file.to_owned(),
+            browser,
+        }];
+    }
 
-        let mut db_vec = Vec::new();
+    let config_dir = match dirs::config_dir() {
+        Some(dir) => dir,
+        None => return Vec::new(),
+    };
+    let mut login_dbs = Vec::new();
+    for browser in BROWSERS.iter() {
+        let browser_dir = config_dir.join(browser.config_dir);
+        let mut profiles = match fs::read_dir(&browser_dir) {
+            Ok(dir) => dir
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| is_profile_dir(path))
+                .collect::<Vec<_>>(),
+            Err(_) => continue,
+        };
+        profiles.sort();
 
-        let thread_results = rx.iter().take(nr_jobs);
-        for thread_result in thread_results {
-            let output = match thread_result.result {
-                Ok(output) => output,
-                Err(err) => {
-                    eprintln!("Error while executing Nightwatch: {}", err);
+        for profile in profiles {
+            for file_name in LOGIN_DB_FILES.iter() {
+                let file = profile.join(file_name);
+                if !file.is_file() {
                     continue;
                 }
-            };
-
-            let mut db_entry = thread_result.db_entry;
-            if output.status.success() {
-                println!(
-                    "Updated password on website {}, with username {}",
-                    &db_entry.url, &db_entry.username
-                );
-                db_vec.push(db_entry);
-            } else {
-                let db_entry_ = db_entry.clone();
-                let err = utils::Error::NightwatchExecError {
-                    db_entry: db_entry_,
-                    output,
-                };
-                eprintln!("{}", err);
-                db_entry.new_password = db_entry.old_password.to_owned();
-                db_vec.push(db_entry);
+                if let Some(file) = file.to_str() {
+                    println!("Found {} login database {}", browser.name, file);
+                    login_dbs.push(LoginDb {
+                        file: file.to_owned(),
+                        browser,
+                    });
+                }
             }
         }
-        let updated_db = DB::new(db_vec);
+    }
+    login_dbs
+}
 
-        match update_db(&config.profile.ptype, source, &updated_db, version) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                println!("{:?}", &updated_db);
-            }
-        };
+/// Chrome keeps a `SingletonLock` symlink in its user data directory while it
+/// is running and holds an exclusive lock on its databases, wait for it to
+/// be closed before touching them.
+fn wait_for_browser_exit(login_db: &LoginDb) -> Result<()> {
+    let lock = match Path::new(&login_db.file)
+        .parent()
+        .map(|p| p.parent())
+        .flatten()
+    {
+        Some(user_data_dir) => user_data_dir.join(SINGLETON_LOCK),
+        None => return Ok(()),
+    };
+
+    let mut waited = Duration::from_secs(0);
+    while fs::symlink_metadata(&lock).is_ok() {
+        if waited >= BROWSER_WAIT {
+            return Err(Error::BrowserRunning {
+                browser: login_db.browser.name.to_owned(),
+                file: login_db.file.to_owned(),
+            });
+        }
+        if waited.as_secs() == 0 {
+            println!(
+                "Please close {} to update {}, waiting...",
+                login_db.browser.name, login_db.file
+            );
+        }
+        thread::sleep(BROWSER_POLL);
+        waited += BROWSER_POLL;
     }
+    Ok(())
 }
 
-fn cipher(encrypt: bool, text: &[u8], version: &[u8], ptype: &ProfileTypes) -> Result<Vec<u8>> {
-    let salt = b"saltysalt";
-    let iv = [32u8; 16];
-    let iterations = 1;
-    let pass;
-    if version == b"v10" {
-        pass = b"peanuts".to_vec();
-    } else if ptype.eq(&ProfileTypes::ChromeG) {
-        pass = keyring::get_chrome_password()
+fn chrome_time_now() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    ((now.as_secs() + CHROME_EPOCH_OFFSET) * 1_000_000 + now.subsec_micros() as u64) as i64
+}
+
+fn has_column(sql_db: &Connection, table: &str, column: &str) -> Result<bool> {
+    let mut stmt = sql_db
+        .prepare(&format!("PRAGMA table_info({})", table))
+        .context(SqliteError)
+        .context(SqlStatementError)?;
+    let columns = stmt
+        .query_map([], |row| row.get::<_, String>(1))
+        .context(SqliteError)
+        .context(SqlQueryError)?;
+    for name in columns {
+        if name.context(SqliteError).context(SqlQueryError)? == column {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn is_profile_dir(path: &Path) -> bool {
+    let name = match path.file_name().map(|name| name.to_str()).flatten() {
+        Some(name) => name,
+        None => return false,
+    };
+    path.is_dir() && (name == "Default" || name.starts_with("Profile "))
+}
+
+fn safe_storage_password(ptype: &ProfileTypes, browser: &ChromiumBrowser) -> Result<Vec<u8>> {
+    if ptype.eq(&ProfileTypes::ChromeG) {
+        return Ok(keyring::get_chrome_password(browser.application)
             .context(KeyringError)
             .context(LibError)?
             .as_bytes()
-            .to_vec();
-    } else {
-        pass = b"".to_vec();
+            .to_vec());
+    }
+
+    let folder = format!("{} Keys", browser.name);
+    let key = format!("{} Safe Storage", browser.name);
+    // Chrome only uses the basic password store if it never saved a secret,
+    // any other KWallet error would lead to the wrong key
+    match keyring::get_kwallet_password(&folder, &key) {
+        Ok(password) => Ok(password.as_bytes().to_vec()),
+        Err(err @ keyring::Error::KWalletEntryMissing { .. }) => {
+            eprintln!("Warning: {}, falling back to the basic password store", err);
+            Ok(b"".to_vec())
+        }
+        Err(err) => Err(err).context(KeyringError).context(LibError),
     }
+}
+
+/// v10 values are encrypted with a fixed password, v11 values with the Safe
+/// Storage password of the browser, which is only looked up once.
+fn key_password(
+    version: &[u8],
+    ptype: &ProfileTypes,
+    browser: &ChromiumBrowser,
+    cache: &mut Option<Vec<u8>>,
+) -> Result<Vec<u8>> {
+    if version == b"v10" {
+        return Ok(b"peanuts".to_vec());
+    }
+    if let Some(password) = cache {
+        return Ok(password.to_owned());
+    }
+    let password = safe_storage_password(ptype, browser)?;
+    *cache = Some(password.to_owned());
+    Ok(password)
+}
+
+fn cipher(encrypt: bool, text: &[u8], version: &[u8], pass: &[u8]) -> Result<Vec<u8>> {
+    let salt = b"saltysalt";
+    let iv = [32u8; 16];
+    let iterations = 1;
 
     let mut key = [32u8; 16];
     pbkdf2_hmac(
-        &pass,
+        pass,
         salt,
         iterations,
         hash::MessageDigest::sha1(),
@@ -156,29 +342,39 @@ fn cipher(encrypt: bool, text: &[u8], version: &[u8], ptype: &ProfileTypes) -> R
     Ok(result)
 }
 
-fn unlock_and_parse_db(ptype: &ProfileTypes, source: &Source) -> Result<(DB, Vec<u8>)> {
-    let sql_db = Connection::open(&source.file)
+fn unlock_and_parse_db(ptype: &ProfileTypes, login_db: &LoginDb) -> Result<(DB, Vec<u8>)> {
+    let sql_db = Connection::open(&login_db.file)
         .context(SqliteError)
         .context(DBOpenError {
-            file: source.file.to_owned(),
+            file: login_db.file.to_owned(),
         })?;
 
     let mut stmt = sql_db
-        .prepare("SELECT action_url, username_value, password_value FROM logins")
+        .prepare(
+            "SELECT origin_url, username_element, username_value, password_element, \
+             signon_realm, action_url, password_value FROM logins",
+        )
         .context(SqliteError)
         .context(SqlStatementError)?;
     let login_iter = stmt
         .query_map([], |row| {
             Ok(Login {
-                origin_url: row.get(0)?,
-                username: row.get(1)?,
-                password: row.get(2)?,
+                id: ChromeLoginId {
+                    origin_url: row.get(0)?,
+                    username_element: row.get(1)?,
+                    username_value: row.get(2)?,
+                    password_element: row.get(3)?,
+                    signon_realm: row.get(4)?,
+                },
+                action_url: row.get(5)?,
+                password: row.get(6)?,
             })
         })
         .context(SqliteError)
         .context(SqlQueryError)?;
 
     let mut version = Vec::new();
+    let mut password_cache = None;
     let mut db_vec = Vec::new();
     for (i, login_) in login_iter.enumerate() {
         let login = login_.map_err(|_| Error::RowError {
@@ -192,41 +388,175 @@ fn unlock_and_parse_db(ptype: &ProfileTypes, source: &Source) -> Result<(DB, Vec
         }
         let encrypted_password: Vec<u8> = login.password;
         version = encrypted_password[0..3].to_ascii_lowercase();
-        let decrypted_u8 = cipher(false, &encrypted_password[3..], &version, ptype)?;
+        let pass = key_password(&version, ptype, login_db.browser, &mut password_cache)?;
+        let decrypted_u8 = cipher(false, &encrypted_password[3..], &version, &pass)?;
         let password = str::from_utf8(&decrypted_u8)
             .context(Utf8Error)
             .context(StringConversionError)?;
 
-        db_vec.push(DBEntry::new(
-            login.origin_url,
-            login.username,
+        let mut db_entry = DBEntry::new(
+            login.id.origin_url.to_owned(),
+            login.id.username_value.to_owned(),
             password.to_owned(),
             get_pw().context(UtilsError).context(LibError)?,
-        ));
+        );
+        db_entry.alt_urls = vec![login.action_url, login.id.signon_realm.to_owned()];
+        db_entry.uuid = Uuid::Chrome(login.id);
+        db_vec.push(db_entry);
     }
     Ok((DB::new(db_vec), version))
 }
 
-fn update_db(ptype: &ProfileTypes, source: &Source, db: &DB, version: Vec<u8>) -> Result<()> {
-    let sql_db = Connection::open(&source.file)
+/// Write all new passwords in one transaction. Rows are matched on Chrome's
+/// primary key, the modification date is updated and the entries are
+/// removed from the insecure credentials, since their password changed.
+fn update_db(
+    ptype: &ProfileTypes,
+    login_db: &LoginDb,
+    db: &DB,
+    version: Vec<u8>,
+) -> Result<Vec<Uuid>> {
+    wait_for_browser_exit(login_db)?;
+    let mut sql_db = Connection::open(&login_db.file)
         .context(SqliteError)
         .context(DBOpenError {
-            file: source.file.to_owned(),
+            file: login_db.file.to_owned(),
         })?;
+    sql_db
+        .busy_timeout(BUSY_TIMEOUT)
+        .context(SqliteError)
+        .context(SqlStatementError)?;
+    let has_date_modified = has_column(&sql_db, "logins", "date_password_modified")?;
+    let has_insecure_credentials = has_column(&sql_db, "insecure_credentials", "parent_id")?;
 
+    let transaction = sql_db
+        .transaction()
+        .context(SqliteError)
+        .context(SqlQueryError)?;
+    let mut password_cache = None;
+    let mut written = Vec::new();
     for entry in &db.entries {
-        let password_u8 = cipher(true, entry.new_password.as_bytes(), &version, ptype)?;
-        let mut query = sql_db
-            .prepare(
-                "UPDATE logins SET password_value = ? WHERE action_url = ? AND username_value = ?",
+        let id = match &entry.uuid {
+            Uuid::Chrome(id) => id,
+            _ => return Err(Error::WrongUuidType),
+        };
+        let row_id: Option<i64> = transaction
+            .query_row(
+                "SELECT rowid FROM logins WHERE origin_url = ? AND username_element = ? \
+                 AND username_value = ? AND password_element = ? AND signon_realm = ?",
+                params![
+                    id.origin_url,
+                    id.username_element,
+                    id.username_value,
+                    id.password_element,
+                    id.signon_realm
+                ],
+                |row| row.get(0),
             )
+            .optional()
             .context(SqliteError)
             .context(SqlQueryError)?;
-        query
-            .execute(params![password_u8, entry.url, entry.username])
+        let row_id = match row_id {
+            Some(row_id) => row_id,
+            None => {
+                eprintln!(
+                    "Warning: Login for {} with username {} is no longer present in {}",
+                    entry.url, entry.username, login_db.file
+                );
+                continue;
+            }
+        };
+
+        let pass = key_password(&version, ptype, login_db.browser, &mut password_cache)?;
+        let password_u8 = cipher(true, entry.new_password.as_bytes(), &version, &pass)?;
+        transaction
+            .execute(
+                "UPDATE logins SET password_value = ? WHERE rowid = ?",
+                params![password_u8, row_id],
+            )
             .context(SqliteError)
             .context(SqlQueryError)?;
+        if has_date_modified {
+            transaction
+                .execute(
+                    "UPDATE logins SET date_password_modified = ? WHERE rowid = ?",
+                    params![chrome_time_now(), row_id],
+                )
+                .context(SqliteError)
+                .context(SqlQueryError)?;
+        }
+        if has_insecure_credentials {
+            transaction
+                .execute(
+                    "DELETE FROM insecure_credentials WHERE parent_id = ?",
+                    params![row_id],
+                )
+                .context(SqliteError)
+                .context(SqlQueryError)?;
+        }
+        written.push(entry.uuid.to_owned());
     }
 
-    Ok(())
+    transaction
+        .commit()
+        .context(SqliteError)
+        .context(SqlQueryError)?;
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::mock::{self, MockBus};
+
+    /// "hunter2 with spaces " encrypted by Chrome with the v10 key
+    const V10_VALUE: &str = "247c3fc54d3f6431033771bf64a7550e2be48fbfbd3512d2ea3ecac89ebfe759";
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn cipher_matches_chrome() {
+        let encrypted = cipher(true, b"hunter2 with spaces ", b"v10", b"peanuts").unwrap();
+        assert_eq!(&encrypted[..3], b"v10");
+        assert_eq!(encrypted[3..], unhex(V10_VALUE)[..]);
+        let decrypted = cipher(false, &encrypted[3..], b"v10", b"peanuts").unwrap();
+        assert_eq!(decrypted, b"hunter2 with spaces ");
+    }
+
+    #[test]
+    fn kwallet_secret_is_used() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, 1, "Chrome Keys", "Chrome Safe Storage", "s3cret");
+        let password = safe_storage_password(&ProfileTypes::ChromeK, &BROWSERS[0]).unwrap();
+        assert_eq!(password, b"s3cret");
+    }
+
+    #[test]
+    fn missing_kwallet_secret_falls_back_to_basic_store() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, 1, "Chromium Keys", "Chromium Safe Storage", "s3cret");
+        let password = safe_storage_password(&ProfileTypes::ChromeK, &BROWSERS[0]).unwrap();
+        assert!(password.is_empty());
+    }
+
+    #[test]
+    fn refused_kwallet_is_an_error() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, -1, "Chrome Keys", "Chrome Safe Storage", "s3cret");
+        assert!(safe_storage_password(&ProfileTypes::ChromeK, &BROWSERS[0]).is_err());
+    }
 }
@@ -1,10 +1,11 @@
 use std::collections::HashMap;
+use std::time::Duration;
 use std::{fmt, fs, io, result, usize};
 
 use snafu::{ResultExt, Snafu};
 use toml::Value;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum BrowserType {
     Firefox,
     Chrome,
@@ -19,10 +20,109 @@ impl fmt::Display for BrowserType {
     }
 }
 
+/// Settings of the browser scripts run in, from the optional `[browser]`
+/// table. They apply to recipes and to the generated Nightwatch
+/// configuration.
+#[derive(Debug, Clone)]
+pub struct BrowserSettings {
+    pub headless: bool,
+    pub args: Vec<String>,
+    pub geckodriver: Option<String>,
+    pub chromedriver: Option<String>,
+    pub window_size: Option<(u64, u64)>,
+    pub output_folder: String,
+}
+
+impl BrowserSettings {
+    /// The configured driver binary of the browser, if any.
+    pub fn driver_path(&self, browser_type: &BrowserType) -> Option<&str> {
+        match browser_type {
+            BrowserType::Firefox => self.geckodriver.as_deref(),
+            BrowserType::Chrome => self.chromedriver.as_deref(),
+        }
+    }
+
+    /// Arguments the browser is started with, headless mode and window size
+    /// included.
+    pub fn browser_args(&self, browser_type: &BrowserType) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.headless {
+            args.push(match browser_type {
+                BrowserType::Firefox => "-headless".to_owned(),
+                BrowserType::Chrome => "--headless".to_owned(),
+            });
+        }
+        if let Some((width, height)) = self.window_size {
+            match browser_type {
+                BrowserType::Firefox => {
+                    args.push(format!("--width={}", width));
+                    args.push(format!("--height={}", height));
+                }
+                BrowserType::Chrome => args.push(format!("--window-size={},{}", width, height)),
+            }
+        }
+        args.extend(self.args.iter().cloned());
+        args
+    }
+}
+
+/// Whether passwords are changed or only the stored credentials are checked.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Update,
+    Check,
+}
+
+/// Limits of a single script run and how often it is retried.
+#[derive(Debug, Clone)]
+pub struct JobPolicy {
+    pub timeout: Duration,
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+impl JobPolicy {
+    pub fn new(timeout: Duration, retries: u32, retry_delay: Duration) -> Self {
+        Self {
+            timeout,
+            retries,
+            retry_delay,
+        }
+    }
+}
+
+/// How jobs are spread over time: jobs on the same domain run one after
+/// another with a delay in between, the start rate over all domains can be
+/// capped.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub domain_delay: Duration,
+    pub domain_jitter: Duration,
+    pub max_starts_per_minute: u32,
+}
+
+impl Schedule {
+    pub fn new(
+        domain_delay: Duration,
+        domain_jitter: Duration,
+        max_starts_per_minute: u32,
+    ) -> Self {
+        Self {
+            domain_delay,
+            domain_jitter,
+            max_starts_per_minute,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Configuration {
+    pub mode: Mode,
     pub browser_type: BrowserType,
+    pub browser: BrowserSettings,
     pub nr_threads: usize,
+    pub jobs: JobPolicy,
+    pub schedule: Schedule,
     pub active_profile: String,
     pub profile: Profile,
     pub sources: Vec<Source>,
@@ -33,7 +133,10 @@ pub struct Configuration {
 impl Configuration {
     pub fn new(
         browser_type: BrowserType,
+        browser: BrowserSettings,
         nr_threads: usize,
+        jobs: JobPolicy,
+        schedule: Schedule,
         active_profile: String,
         profile: Profile,
         sources: Vec<Source>,
@@ -41,8 +144,12 @@ impl Configuration {
         urls: HashMap<String, String>,
     ) -> Self {
         Self {
+            mode: Mode::Update,
             browser_type,
+            browser,
             nr_threads,
+            jobs,
+            schedule,
             active_profile,
             profile,
             sources,
@@ -56,9 +163,18 @@ impl Configuration {
 pub enum ProfileTypes {
     Kdbx,
     Pass,
+    Gopass,
+    Passage,
     Pwsafe,
     ChromeG,
     ChromeK,
+    Firefox,
+    BitwardenJson,
+    Csv,
+    OnePassword,
+    Netrc,
+    GitCredentials,
+    SecretService,
 }
 
 impl fmt::Display for ProfileTypes {
@@ -66,9 +182,18 @@ impl fmt::Display for ProfileTypes {
         match *self {
             ProfileTypes::Kdbx => write!(f, "kdbx"),
             ProfileTypes::Pass => write!(f, "pass"),
+            ProfileTypes::Gopass => write!(f, "gopass"),
+            ProfileTypes::Passage => write!(f, "passage"),
             ProfileTypes::Pwsafe => write!(f, "pwsafe"),
             ProfileTypes::ChromeG => write!(f, "chrome-gnome"),
             ProfileTypes::ChromeK => write!(f, "chrome-kde"),
+            ProfileTypes::Firefox => write!(f, "firefox"),
+            ProfileTypes::BitwardenJson => write!(f, "bitwarden-json"),
+            ProfileTypes::Csv => write!(f, "csv"),
+            ProfileTypes::OnePassword => write!(f, "onepassword-1pux"),
+            ProfileTypes::Netrc => write!(f, "netrc"),
+            ProfileTypes::GitCredentials => write!(f, "git-credentials"),
+            ProfileTypes::SecretService => write!(f, "secret-service"),
         }
     }
 }
@@ -90,14 +215,27 @@ pub struct Source {
     pub name: String,
     pub file: String,
     pub blocklist: Vec<String>,
+    pub layouts: Vec<String>,
+    pub columns: HashMap<String, String>,
+    pub output_columns: HashMap<String, String>,
 }
 
 impl Source {
-    pub fn new(name: String, file: String, blocklist: Vec<String>) -> Self {
+    pub fn new(
+        name: String,
+        file: String,
+        blocklist: Vec<String>,
+        layouts: Vec<String>,
+        columns: HashMap<String, String>,
+        output_columns: HashMap<String, String>,
+    ) -> Self {
         Self {
             name,
             file,
             blocklist,
+            layouts,
+            columns,
+            output_columns,
         }
     }
 }
@@ -114,8 +252,35 @@ impl Script {
     }
 }
 
-const ALLOWED_PROFILE_TYPES: [&str; 5] = ["kdbx", "pass", "pwsafe", "chrome-gnome", "chrome-kde"];
-const PROFILE_TYPES_WITH_SOURCE: [&str; 4] = ["kdbx", "pwsafe", "chrome-gnome", "chrome-kde"];
+const ALLOWED_PROFILE_TYPES: [&str; 14] = [
+    "kdbx",
+    "pass",
+    "gopass",
+    "passage",
+    "pwsafe",
+    "chrome-gnome",
+    "chrome-kde",
+    "firefox",
+    "bitwarden-json",
+    "csv",
+    "onepassword-1pux",
+    "netrc",
+    "git-credentials",
+    "secret-service",
+];
+const PROFILE_TYPES_WITH_SOURCE: [&str; 6] = [
+    "kdbx",
+    "pwsafe",
+    "firefox",
+    "bitwarden-json",
+    "csv",
+    "onepassword-1pux",
+];
+const DEFAULT_SCRIPT_TIMEOUT: u64 = 300;
+const DEFAULT_RETRY_DELAY: u64 = 30;
+const DEFAULT_DOMAIN_DELAY: u64 = 10;
+const DEFAULT_DOMAIN_JITTER: u64 = 10;
+const DEFAULT_OUTPUT_FOLDER: &str = "./reports";
 
 #[derive(Debug, Snafu)]
 pub enum Error {
@@ -135,6 +300,8 @@ pub enum Error {
     ConfigBrowserTypeWrong,
     #[snafu(display("Missing browser type field in config file"))]
     ConfigBrowserTypeMissing,
+    #[snafu(display("window_size in [browser] has to be [width, height], e.g. [1280, 1024]"))]
+    BrowserWindowSizeWrong,
 
     //*********************************************************************************
     //Active Profile Errors
@@ -218,6 +385,8 @@ pub fn parse_config(path: &str) -> Result<Configuration> {
         return Err(Error::ConfigBrowserTypeWrong);
     }
 
+    let browser = parse_browser(config.get("browser"))?;
+
     let nr_threads = config
         .get("nr_threads")
         .unwrap_or(&Value::Integer(1))
@@ -225,6 +394,26 @@ pub fn parse_config(path: &str) -> Result<Configuration> {
         .unwrap_or(1)
         .abs() as usize;
 
+    let seconds = |key: &str, default: u64| {
+        let secs = config.get(key).map(|v| v.as_integer()).flatten();
+        Duration::from_secs(secs.map(|s| s.abs() as u64).unwrap_or(default))
+    };
+    let integer = |key: &str| {
+        let value = config.get(key).map(|v| v.as_integer()).flatten();
+        value.unwrap_or(0).abs() as u32
+    };
+    let retries = integer("retries");
+    let jobs = JobPolicy::new(
+        seconds("script_timeout", DEFAULT_SCRIPT_TIMEOUT),
+        retries,
+        seconds("retry_delay", DEFAULT_RETRY_DELAY),
+    );
+    let schedule = Schedule::new(
+        seconds("domain_delay", DEFAULT_DOMAIN_DELAY),
+        seconds("domain_jitter", DEFAULT_DOMAIN_JITTER),
+        integer("max_starts_per_minute"),
+    );
+
     let active_profilev = config
         .get("active_profile")
         .ok_or(Error::ActiveProfileMissingField {
@@ -298,7 +487,10 @@ pub fn parse_config(path: &str) -> Result<Configuration> {
 
     Ok(Configuration::new(
         browser_type,
+        browser,
         nr_threads,
+        jobs,
+        schedule,
         active_profile,
         profile,
         sources,
@@ -311,9 +503,18 @@ fn create_profiletype_map() -> HashMap<String, ProfileTypes> {
     let mut profile_map = HashMap::new();
     profile_map.insert("kdbx".to_owned(), ProfileTypes::Kdbx);
     profile_map.insert("pass".to_owned(), ProfileTypes::Pass);
+    profile_map.insert("gopass".to_owned(), ProfileTypes::Gopass);
+    profile_map.insert("passage".to_owned(), ProfileTypes::Passage);
     profile_map.insert("pwsafe".to_owned(), ProfileTypes::Pwsafe);
     profile_map.insert("chrome-gnome".to_owned(), ProfileTypes::ChromeG);
     profile_map.insert("chrome-kde".to_owned(), ProfileTypes::ChromeK);
+    profile_map.insert("firefox".to_owned(), ProfileTypes::Firefox);
+    profile_map.insert("bitwarden-json".to_owned(), ProfileTypes::BitwardenJson);
+    profile_map.insert("csv".to_owned(), ProfileTypes::Csv);
+    profile_map.insert("onepassword-1pux".to_owned(), ProfileTypes::OnePassword);
+    profile_map.insert("netrc".to_owned(), ProfileTypes::Netrc);
+    profile_map.insert("git-credentials".to_owned(), ProfileTypes::GitCredentials);
+    profile_map.insert("secret-service".to_owned(), ProfileTypes::SecretService);
 
     profile_map
 }
@@ -360,8 +561,18 @@ fn parse_source(source: &Value, profile: &Profile) -> Result<Source> {
     };
 
     let blocklist = parse_blocklist(source);
-
-    Ok(Source::new(name, file, blocklist))
+    let layouts = parse_string_array(source, "layouts");
+    let columns = parse_string_table(source, "columns");
+    let output_columns = parse_string_table(source, "output_columns");
+
+    Ok(Source::new(
+        name,
+        file,
+        blocklist,
+        layouts,
+        columns,
+        output_columns,
+    ))
 }
 
 fn parse_script(script: &Value) -> Result<Script> {
@@ -377,17 +588,77 @@ fn parse_script(script: &Value) -> Result<Script> {
     Ok(Script::new(dir, blocklist))
 }
 
+/// Browsers run headless unless `headless = false` is set.
+fn parse_browser(browser: Option<&Value>) -> Result<BrowserSettings> {
+    let empty = Value::Table(toml::map::Map::new());
+    let browser = browser.unwrap_or(&empty);
+    let string = |key: &str| {
+        browser
+            .get(key)
+            .map(|v| v.as_str())
+            .flatten()
+            .map(|v| v.to_owned())
+    };
+
+    let window_size = match browser.get("window_size") {
+        Some(size) => {
+            let size = size.as_array().ok_or(Error::BrowserWindowSizeWrong)?;
+            let dimension = |i: usize| {
+                size.get(i)
+                    .map(|v| v.as_integer())
+                    .flatten()
+                    .filter(|v| *v > 0)
+                    .map(|v| v as u64)
+                    .ok_or(Error::BrowserWindowSizeWrong)
+            };
+            if size.len() != 2 {
+                return Err(Error::BrowserWindowSizeWrong);
+            }
+            Some((dimension(0)?, dimension(1)?))
+        }
+        None => None,
+    };
+
+    Ok(BrowserSettings {
+        headless: browser
+            .get("headless")
+            .map(|v| v.as_bool())
+            .flatten()
+            .unwrap_or(true),
+        args: parse_string_array(browser, "args"),
+        geckodriver: string("geckodriver"),
+        chromedriver: string("chromedriver"),
+        window_size,
+        output_folder: string("output_folder").unwrap_or_else(|| DEFAULT_OUTPUT_FOLDER.to_owned()),
+    })
+}
+
 fn parse_blocklist(value: &Value) -> Vec<String> {
-    let blocklist_r = value.get("blocklist").map(|b| b.as_array()).flatten();
-    let blocklist_v = match blocklist_r {
-        Some(b) => b.to_vec(),
+    parse_string_array(value, "blocklist")
+}
+
+fn parse_string_array(value: &Value, key: &str) -> Vec<String> {
+    let array_r = value.get(key).map(|a| a.as_array()).flatten();
+    let array_v = match array_r {
+        Some(a) => a.to_vec(),
         None => Vec::new(),
     };
 
-    let mut blocklist = Vec::new();
-    for b in blocklist_v {
-        blocklist.push(b.to_string().replace("\"", ""));
+    let mut array = Vec::new();
+    for a in array_v {
+        array.push(a.to_string().replace("\"", ""));
+    }
+
+    array
+}
+
+fn parse_string_table(value: &Value, key: &str) -> HashMap<String, String> {
+    let mut table = HashMap::new();
+    if let Some(table_v) = value.get(key).map(|t| t.as_table()).flatten() {
+        for (k, e) in table_v {
+            table.insert(k.to_string(), e.to_string().replace("\"", ""));
+        }
     }
 
-    blocklist
+    table
 }
@@ -0,0 +1,421 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::{io, result};
+
+use ::csv::{ReaderBuilder, StringRecord, Writer};
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{Configuration, Source};
+use crate::utils::{
+    self, commit_results, get_pw, run_update_threads, write_private_file, DBEntry, Uuid, DB,
+};
+
+const UPDATED_SUFFIX: &str = "updated";
+
+/// Fields of an entry and the column names the exports of Chrome, Firefox,
+/// LastPass, 1Password, KeePassXC and Bitwarden use for them.
+const FIELDS: [(&str, &[&str]); 5] = [
+    ("url", &["url", "login_uri", "website", "web site"]),
+    (
+        "username",
+        &["username", "login_username", "user name", "login"],
+    ),
+    ("password", &["password", "login_password"]),
+    ("title", &["title", "name"]),
+    ("notes", &["notes", "note", "extra", "comments"]),
+];
+
+#[derive(Debug, Snafu)]
+enum LibraryError {
+    IoError { source: io::Error },
+    CsvError { source: ::csv::Error },
+    UtilsError { source: utils::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("Could not open \'{}\': {}", file, source))]
+    OpenFailed {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Column \'{}\' is not present in \'{}\'", column, file))]
+    ColumnMissing {
+        column: String,
+        file: String,
+    },
+    #[snafu(display("No {} column found in \'{}\', set it in columns", field, file))]
+    FieldMissing {
+        field: &'static str,
+        file: String,
+    },
+    #[snafu(display(
+        "Unknown field \'{}\' in column mapping, use one of url, username, password, title, notes",
+        field
+    ))]
+    UnknownField {
+        field: String,
+    },
+    #[snafu(display("Could not write \'{}\': {}", file, source))]
+    WriteFailed {
+        file: String,
+        source: LibraryError,
+    },
+    LibError {
+        source: LibraryError,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// Index of the column of every field which is present in the file.
+#[derive(Debug)]
+struct Columns {
+    indices: HashMap<&'static str, usize>,
+}
+
+impl Columns {
+    /// The value as it is in the file, passwords and notes may start or end
+    /// with spaces.
+    fn get<'a>(&self, record: &'a StringRecord, field: &str) -> &'a str {
+        self.indices
+            .get(field)
+            .map(|i| record.get(*i))
+            .flatten()
+            .unwrap_or("")
+    }
+}
+
+#[derive(Debug)]
+struct CsvFile {
+    file: PathBuf,
+    headers: StringRecord,
+    records: Vec<StringRecord>,
+    columns: Columns,
+}
+
+pub fn run(config: &Configuration) {
+    for source in &config.sources {
+        let mut csv_file = match read_csv(source) {
+            Ok(csv_file) => csv_file,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+        let db = match parse_records(&csv_file) {
+            Ok(db) => db,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+
+        let (tx, rx) = channel();
+        run_update_threads(&db, &source.blocklist, config, tx);
+
+        commit_results(rx, false, |updated_db| {
+            write_csv(source, &mut csv_file, updated_db)
+        });
+    }
+}
+
+fn field_name(field: &str) -> Result<&'static str> {
+    FIELDS
+        .iter()
+        .map(|(name, _)| *name)
+        .find(|name| *name == field)
+        .ok_or(Error::UnknownField {
+            field: field.to_owned(),
+        })
+}
+
+/// Use the configured column for a field or look for one of its known names.
+fn find_columns(source: &Source, headers: &StringRecord) -> Result<Columns> {
+    let position = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name.trim()))
+    };
+
+    let mut indices = HashMap::new();
+    for (field, column) in &source.columns {
+        let index = position(column).ok_or(Error::ColumnMissing {
+            column: column.to_owned(),
+            file: source.file.to_owned(),
+        })?;
+        indices.insert(field_name(field)?, index);
+    }
+    for (field, aliases) in FIELDS.iter() {
+        if indices.contains_key(field) {
+            continue;
+        }
+        if let Some(index) = aliases.iter().find_map(|alias| position(alias)) {
+            indices.insert(*field, index);
+        }
+    }
+
+    for field in ["url", "username", "password"].iter() {
+        if !indices.contains_key(field) {
+            return Err(Error::FieldMissing {
+                field,
+                file: source.file.to_owned(),
+            });
+        }
+    }
+    Ok(Columns { indices })
+}
+
+fn read_csv(source: &Source) -> Result<CsvFile> {
+    let err = OpenFailed {
+        file: source.file.to_owned(),
+    };
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .from_path(&source.file)
+        .context(CsvError)
+        .context(err.clone())?;
+    let headers = reader
+        .headers()
+        .context(CsvError)
+        .context(err.clone())?
+        .to_owned();
+    let records = reader
+        .records()
+        .collect::<result::Result<Vec<_>, _>>()
+        .context(CsvError)
+        .context(err)?;
+    let columns = find_columns(source, &headers)?;
+
+    Ok(CsvFile {
+        file: PathBuf::from(&source.file),
+        headers,
+        records,
+        columns,
+    })
+}
+
+fn parse_records(csv_file: &CsvFile) -> Result<DB> {
+    let mut db_vec = Vec::new();
+    for (i, record) in csv_file.records.iter().enumerate() {
+        let columns = &csv_file.columns;
+        let url = columns.get(record, "url").trim();
+        let username = columns.get(record, "username").trim();
+        let password = columns.get(record, "password");
+        if url.is_empty() || username.is_empty() || password.is_empty() {
+            continue;
+        }
+
+        let mut db_entry = DBEntry::new(
+            url.to_owned(),
+            username.to_owned(),
+            password.to_owned(),
+            get_pw().context(UtilsError).context(LibError)?,
+        );
+        let title = columns.get(record, "title").trim();
+        if !title.is_empty() {
+            db_entry.alt_urls.push(title.to_owned());
+        }
+        db_entry.uuid = Uuid::Csv(i);
+        db_vec.push(db_entry);
+    }
+
+    Ok(DB::new(db_vec))
+}
+
+/// Path next to the CSV file, e.g. `passwords.updated.csv`.
+fn updated_path(file: &Path) -> PathBuf {
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    file.with_file_name(format!("{}.{}.csv", stem, UPDATED_SUFFIX))
+}
+
+/// Convert headers and records to the `output_columns` of the source, in the
+/// order of `FIELDS`.
+fn convert_records(
+    source: &Source,
+    csv_file: &CsvFile,
+) -> Result<(StringRecord, Vec<StringRecord>)> {
+    let mut fields = Vec::new();
+    for (field, _) in FIELDS.iter() {
+        if let Some(column) = source.output_columns.get(*field) {
+            fields.push((*field, column.to_owned()));
+        }
+    }
+    for field in source.output_columns.keys() {
+        field_name(field)?;
+    }
+
+    let headers = fields
+        .iter()
+        .map(|(_, column)| column.as_str())
+        .collect::<StringRecord>();
+    let records = csv_file
+        .records
+        .iter()
+        .map(|record| {
+            fields
+                .iter()
+                .map(|(field, _)| csv_file.columns.get(record, field))
+                .collect::<StringRecord>()
+        })
+        .collect();
+    Ok((headers, records))
+}
+
+/// Write all rows with the new passwords to a new file next to the original,
+/// optionally with the columns of another password manager.
+fn write_csv(source: &Source, csv_file: &mut CsvFile, db: &DB) -> Result<Vec<Uuid>> {
+    if db.entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    let password_index = *csv_file.columns.indices.get("password").unwrap_or(&0);
+    let mut written = Vec::new();
+    for db_entry in &db.entries {
+        let i = match db_entry.uuid {
+            Uuid::Csv(i) => i,
+            _ => continue,
+        };
+        if let Some(record) = csv_file.records.get_mut(i) {
+            *record = record
+                .iter()
+                .enumerate()
+                .map(|(j, value)| {
+                    if j == password_index {
+                        db_entry.new_password.as_str()
+                    } else {
+                        value
+                    }
+                })
+                .collect();
+            written.push(db_entry.uuid.to_owned());
+        }
+    }
+
+    let (headers, records) = if source.output_columns.is_empty() {
+        (csv_file.headers.to_owned(), csv_file.records.to_owned())
+    } else {
+        convert_records(source, csv_file)?
+    };
+
+    let output_file = updated_path(&csv_file.file);
+    let err = WriteFailed {
+        file: output_file.to_string_lossy().into_owned(),
+    };
+    let mut writer = Writer::from_writer(Vec::new());
+    writer
+        .write_record(&headers)
+        .context(CsvError)
+        .context(err.clone())?;
+    for record in &records {
+        writer
+            .write_record(record)
+            .context(CsvError)
+            .context(err.clone())?;
+    }
+    let content = writer
+        .into_inner()
+        .map_err(|err| err.into_error())
+        .context(IoError)
+        .context(err.clone())?;
+    write_private_file(&output_file, &content)
+        .context(IoError)
+        .context(err)?;
+
+    println!("Wrote {}", output_file.to_string_lossy());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv_file(headers: &[&str], rows: &[&[&str]]) -> CsvFile {
+        let headers = headers.iter().collect::<StringRecord>();
+        let source = Source::new(
+            "csv".to_owned(),
+            "passwords.csv".to_owned(),
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            HashMap::new(),
+        );
+        CsvFile {
+            file: PathBuf::from("passwords.csv"),
+            columns: find_columns(&source, &headers).unwrap(),
+            headers,
+            records: rows.iter().map(|r| r.iter().collect()).collect(),
+        }
+    }
+
+    #[test]
+    fn passwords_keep_their_spaces() {
+        let csv_file = csv_file(
+            &[" URL ", "Username", "Password", "Notes"],
+            &[&[" https://example.com ", " alice ", " pa ss ", "  note "]],
+        );
+        let db = parse_records(&csv_file).unwrap();
+        assert_eq!(db.entries[0].url, "https://example.com");
+        assert_eq!(db.entries[0].username, "alice");
+        assert_eq!(db.entries[0].old_password, " pa ss ");
+    }
+
+    #[test]
+    fn converted_records_keep_values() {
+        let csv_file = csv_file(
+            &["url", "username", "password", "notes"],
+            &[&["https://example.com", "alice", " pa ss ", "  note "]],
+        );
+        let mut output_columns = HashMap::new();
+        output_columns.insert("password".to_owned(), "login_password".to_owned());
+        output_columns.insert("notes".to_owned(), "notes".to_owned());
+        let source = Source::new(
+            "csv".to_owned(),
+            "passwords.csv".to_owned(),
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            output_columns,
+        );
+        let (headers, records) = convert_records(&source, &csv_file).unwrap();
+        assert_eq!(headers, vec!["login_password", "notes"]);
+        assert_eq!(records[0], vec![" pa ss ", "  note "]);
+    }
+
+    #[test]
+    fn updated_csv_is_private() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut csv_file = csv_file(
+            &["url", "username", "password"],
+            &[&["https://example.com", "alice", "old"]],
+        );
+        csv_file.file = dir.path().join("passwords.csv");
+        let output_file = updated_path(&csv_file.file);
+        fs::write(&output_file, b"stale").unwrap();
+        fs::set_permissions(&output_file, fs::Permissions::from_mode(0o644)).unwrap();
+        let mut db = parse_records(&csv_file).unwrap();
+        db.entries[0].new_password = "new".to_owned();
+        let source = Source::new(
+            "csv".to_owned(),
+            "passwords.csv".to_owned(),
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            HashMap::new(),
+        );
+        let written = write_csv(&source, &mut csv_file, &db).unwrap();
+        assert_eq!(written, vec![db.entries[0].uuid.to_owned()]);
+        let mode = fs::metadata(&output_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, utils::PRIVATE_FILE_MODE);
+        assert_eq!(
+            fs::read_to_string(&output_file).unwrap(),
+            "url,username,password\nhttps://example.com,alice,new\n"
+        );
+    }
+}
@@ -0,0 +1,769 @@
+use std::collections::HashMap;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{fs, io, result};
+
+use openssl::hash::{hash, MessageDigest};
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::symm::{decrypt, encrypt, Cipher};
+use rpassword::read_password;
+use rusqlite::Connection;
+use serde_json::Value;
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{Configuration, Source};
+use crate::utils::{
+    self, commit_results, get_pw, run_update_threads, write_private_file, DBEntry, Uuid, DB,
+};
+
+const LOGINS_FILE: &str = "logins.json";
+const KEY_DB_FILE: &str = "key4.db";
+const LOCK_LINK: &str = "lock";
+const PARENT_LOCK_FILE: &str = ".parentlock";
+const PASSWORD_CHECK: &[u8] = b"password-check";
+
+const OID_PBES2: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x05, 0x0d];
+const OID_PBE_SHA1_3DES: &[u8] = &[
+    0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x0c, 0x05, 0x01, 0x03,
+];
+const OID_DES_EDE3_CBC: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x03, 0x07];
+const OID_AES256_CBC: &[u8] = &[0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x01, 0x2a];
+
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OBJECT_ID: u8 = 0x06;
+const TAG_SEQUENCE: u8 = 0x30;
+
+#[derive(Debug, Snafu)]
+enum LibraryError {
+    IoError { source: io::Error },
+    SqliteError { source: rusqlite::Error },
+    OpensslError { source: openssl::error::ErrorStack },
+    JsonError { source: serde_json::Error },
+    Base64Error { source: base64::DecodeError },
+    UtilsError { source: utils::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("Could not open \'{}\': {}", file, source))]
+    OpenFailed {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not query \'{}\': {}", file, source))]
+    KeyDbError {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("\'{}\' is not a valid logins file", file))]
+    LoginsWrongFormat {
+        file: String,
+    },
+    #[snafu(display("Encrypted value is not valid DER"))]
+    DerError,
+    #[snafu(display("Encryption scheme is not supported"))]
+    UnsupportedScheme,
+    #[snafu(display("No key for the encrypted value was found in the key database"))]
+    KeyMissing,
+    #[snafu(display("Could not decrypt value: {}", source))]
+    DecryptError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not encrypt value: {}", source))]
+    EncryptError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not read the primary password: {}", source))]
+    PasswordReadFailed {
+        source: LibraryError,
+    },
+    #[snafu(display("Firefox is still running, please close it to update \'{}\'", file))]
+    BrowserRunning {
+        file: String,
+    },
+    #[snafu(display("Could not update \'{}\' with {}", file, source))]
+    DbUpdateFailed {
+        file: String,
+        source: LibraryError,
+    },
+    LibError {
+        source: LibraryError,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// Minimal DER reader, just enough for the structures NSS stores.
+#[derive(Debug)]
+enum Der<'a> {
+    Sequence(Vec<Der<'a>>),
+    OctetString(&'a [u8]),
+    ObjectId(&'a [u8]),
+    Integer(&'a [u8]),
+    Other,
+}
+
+impl<'a> Der<'a> {
+    fn parse(data: &'a [u8]) -> Result<(Der<'a>, &'a [u8])> {
+        if data.len() < 2 {
+            return Err(Error::DerError);
+        }
+        let tag = data[0];
+        let (len, header) = if data[1] & 0x80 == 0 {
+            (data[1] as usize, 2)
+        } else {
+            let nr_bytes = (data[1] & 0x7f) as usize;
+            if nr_bytes == 0 || nr_bytes > 4 || data.len() < 2 + nr_bytes {
+                return Err(Error::DerError);
+            }
+            let len = data[2..2 + nr_bytes]
+                .iter()
+                .fold(0usize, |len, b| (len << 8) | *b as usize);
+            (len, 2 + nr_bytes)
+        };
+        if data.len() < header + len {
+            return Err(Error::DerError);
+        }
+        let content = &data[header..header + len];
+        let rest = &data[header + len..];
+
+        let value = match tag {
+            TAG_SEQUENCE => {
+                let mut items = Vec::new();
+                let mut remaining = content;
+                while !remaining.is_empty() {
+                    let (item, next) = Der::parse(remaining)?;
+                    items.push(item);
+                    remaining = next;
+                }
+                Der::Sequence(items)
+            }
+            TAG_OCTET_STRING => Der::OctetString(content),
+            TAG_OBJECT_ID => Der::ObjectId(content),
+            TAG_INTEGER => Der::Integer(content),
+            _ => Der::Other,
+        };
+        Ok((value, rest))
+    }
+
+    fn items(&self) -> Result<&[Der<'a>]> {
+        match self {
+            Der::Sequence(items) => Ok(items),
+            _ => Err(Error::DerError),
+        }
+    }
+
+    fn item(&self, index: usize) -> Result<&Der<'a>> {
+        self.items()?.get(index).ok_or(Error::DerError)
+    }
+
+    fn bytes(&self) -> Result<&'a [u8]> {
+        match self {
+            Der::OctetString(bytes) | Der::ObjectId(bytes) | Der::Integer(bytes) => Ok(bytes),
+            _ => Err(Error::DerError),
+        }
+    }
+
+    fn integer(&self) -> Result<usize> {
+        Ok(self
+            .bytes()?
+            .iter()
+            .fold(0usize, |value, b| (value << 8) | *b as usize))
+    }
+}
+
+fn der_encode(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut result = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        result.push(len as u8);
+    } else {
+        let len_bytes = len.to_be_bytes();
+        let skip = len_bytes.iter().take_while(|b| **b == 0).count();
+        result.push(0x80 | (len_bytes.len() - skip) as u8);
+        result.extend_from_slice(&len_bytes[skip..]);
+    }
+    result.extend_from_slice(content);
+    result
+}
+
+/// Decrypted keys of key4.db, indexed by their key id.
+struct KeyStore {
+    keys: HashMap<Vec<u8>, Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct Profile {
+    dir: PathBuf,
+    logins_file: PathBuf,
+    key_db_file: PathBuf,
+}
+
+impl Profile {
+    fn new(dir: &str) -> Self {
+        let dir = Path::new(dir);
+        Self {
+            dir: dir.to_owned(),
+            logins_file: dir.join(LOGINS_FILE),
+            key_db_file: dir.join(KEY_DB_FILE),
+        }
+    }
+
+    /// Firefox keeps a `lock` symlink to `<host>:+<pid>` in the profile while
+    /// it is running and holds an fcntl lock on `.parentlock`. A running
+    /// Firefox overwrites logins.json with its own state, so nothing may be
+    /// written until it is closed.
+    fn check_browser_closed(&self) -> Result<()> {
+        let lock_pid = fs::read_link(self.dir.join(LOCK_LINK))
+            .ok()
+            .map(|target| {
+                target
+                    .to_string_lossy()
+                    .rsplit('+')
+                    .next()
+                    .map(|pid| pid.parse::<libc::pid_t>().ok())
+                    .flatten()
+            })
+            .flatten();
+        let lock_held = lock_pid
+            .map(|pid| unsafe { libc::kill(pid, 0) } == 0)
+            .unwrap_or(false);
+        if lock_held || parent_lock_held(&self.dir.join(PARENT_LOCK_FILE)) {
+            return Err(Error::BrowserRunning {
+                file: self.logins_file.to_string_lossy().into_owned(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Whether another process holds the fcntl lock of the file.
+fn parent_lock_held(path: &Path) -> bool {
+    let file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let mut lock: libc::flock = unsafe { std::mem::zeroed() };
+    lock.l_type = libc::F_WRLCK as libc::c_short;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    let result = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut lock) };
+    result == 0 && lock.l_type != libc::F_UNLCK as libc::c_short
+}
+
+pub fn run(config: &Configuration) {
+    for source in &config.sources {
+        let profile = Profile::new(&source.file);
+        if let Err(err) = profile.check_browser_closed() {
+            eprintln!("Error: {}", err);
+            continue;
+        }
+        let key_store = match unlock_key_db(source, &profile) {
+            Ok(key_store) => key_store,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+        let (db, mut logins) = match parse_logins(&profile, &key_store) {
+            Ok(values) => values,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+
+        let (tx, rx) = channel();
+        run_update_threads(&db, &source.blocklist, config, tx);
+
+        commit_results(rx, false, |updated_db| {
+            write_logins(&profile, &key_store, &mut logins, updated_db)
+        });
+    }
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+    let pkey = PKey::hmac(key)
+        .context(OpensslError)
+        .context(DecryptError)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &pkey)
+        .context(OpensslError)
+        .context(DecryptError)?;
+    signer
+        .update(data)
+        .context(OpensslError)
+        .context(DecryptError)?;
+    signer
+        .sign_to_vec()
+        .context(OpensslError)
+        .context(DecryptError)
+}
+
+/// Decrypt a PBE protected item of key4.db, either PBES2 with PBKDF2 and
+/// AES-256-CBC or the legacy SHA1 and 3DES scheme.
+fn decrypt_pbe(item: &[u8], global_salt: &[u8], primary_password: &[u8]) -> Result<Vec<u8>> {
+    let (der, _) = Der::parse(item)?;
+    let algorithm = der.item(0)?;
+    let ciphertext = der.item(1)?.bytes()?;
+    let oid = algorithm.item(0)?.bytes()?;
+
+    let mut salted_password = global_salt.to_vec();
+    salted_password.extend_from_slice(primary_password);
+    let hashed_password = hash(MessageDigest::sha1(), &salted_password)
+        .context(OpensslError)
+        .context(DecryptError)?;
+
+    if oid == OID_PBES2 {
+        let params = algorithm.item(1)?;
+        let kdf_params = params.item(0)?.item(1)?;
+        let entry_salt = kdf_params.item(0)?.bytes()?;
+        let iterations = kdf_params.item(1)?.integer()?;
+        let key_length = kdf_params.item(2)?.integer()?;
+        let cipher_params = params.item(1)?;
+        if cipher_params.item(0)?.bytes()? != OID_AES256_CBC {
+            return Err(Error::UnsupportedScheme);
+        }
+        // NSS stores only the last 14 bytes of the IV
+        let iv_stored = cipher_params.item(1)?.bytes()?;
+        let mut iv = Vec::new();
+        if iv_stored.len() == 14 {
+            iv.extend_from_slice(&[0x04, 0x0e]);
+        }
+        iv.extend_from_slice(iv_stored);
+
+        let mut key = vec![0u8; key_length];
+        pbkdf2_hmac(
+            &hashed_password,
+            entry_salt,
+            iterations,
+            MessageDigest::sha256(),
+            &mut key,
+        )
+        .context(OpensslError)
+        .context(DecryptError)?;
+        decrypt(Cipher::aes_256_cbc(), &key, Some(&iv), ciphertext)
+            .context(OpensslError)
+            .context(DecryptError)
+    } else if oid == OID_PBE_SHA1_3DES {
+        let entry_salt = algorithm.item(1)?.item(0)?.bytes()?;
+        let mut padded_salt = entry_salt.to_vec();
+        padded_salt.resize(20, 0);
+
+        let mut chp_input = hashed_password.to_vec();
+        chp_input.extend_from_slice(entry_salt);
+        let chp = hash(MessageDigest::sha1(), &chp_input)
+            .context(OpensslError)
+            .context(DecryptError)?;
+        let k1 = hmac_sha1(&chp, &[padded_salt.as_slice(), entry_salt].concat())?;
+        let tk = hmac_sha1(&chp, &padded_salt)?;
+        let k2 = hmac_sha1(&chp, &[tk.as_slice(), entry_salt].concat())?;
+        let k = [k1, k2].concat();
+        decrypt(
+            Cipher::des_ede3_cbc(),
+            &k[..24],
+            Some(&k[k.len() - 8..]),
+            ciphertext,
+        )
+        .context(OpensslError)
+        .context(DecryptError)
+    } else {
+        Err(Error::UnsupportedScheme)
+    }
+}
+
+fn read_keys(
+    key_db: &Connection,
+    global_salt: &[u8],
+    primary_password: &[u8],
+) -> Result<HashMap<Vec<u8>, Vec<u8>>> {
+    let mut stmt = key_db
+        .prepare("SELECT a11, a102 FROM nssPrivate")
+        .context(SqliteError)
+        .context(LibError)?;
+    let rows = stmt
+        .query_map([], |row| Ok((row.get::<_, Vec<u8>>(0)?, row.get(1)?)))
+        .context(SqliteError)
+        .context(LibError)?;
+
+    let mut keys = HashMap::new();
+    for row in rows {
+        let (encrypted_key, key_id) = row.context(SqliteError).context(LibError)?;
+        let key = decrypt_pbe(&encrypted_key, global_salt, primary_password)?;
+        keys.insert(key_id, key);
+    }
+    Ok(keys)
+}
+
+fn unlock_key_db(source: &Source, profile: &Profile) -> Result<KeyStore> {
+    let key_db_file = profile.key_db_file.to_string_lossy().into_owned();
+    let key_db = Connection::open(&profile.key_db_file)
+        .context(SqliteError)
+        .context(OpenFailed {
+            file: key_db_file.to_owned(),
+        })?;
+    let (global_salt, password_check): (Vec<u8>, Vec<u8>) = key_db
+        .query_row(
+            "SELECT item1, item2 FROM metadata WHERE id = 'password'",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )
+        .context(SqliteError)
+        .context(KeyDbError {
+            file: key_db_file.to_owned(),
+        })?;
+
+    // Most profiles have no primary password, only ask if that fails. A
+    // wrong password either fails to decrypt or yields a wrong check value.
+    let mut primary_password = String::new();
+    let mut asked = false;
+    loop {
+        match decrypt_pbe(&password_check, &global_salt, primary_password.as_bytes()) {
+            Ok(value) if value.starts_with(PASSWORD_CHECK) => break,
+            Ok(_) | Err(Error::DecryptError { .. }) => (),
+            Err(err) => return Err(err),
+        }
+        if asked {
+            println!("Wrong password! Please try again:");
+        } else {
+            println!(
+                "Please enter the primary password for {} at {}",
+                source.name, source.file
+            );
+            asked = true;
+        }
+        primary_password = read_password()
+            .context(IoError)
+            .context(PasswordReadFailed)?;
+    }
+
+    let keys = read_keys(&key_db, &global_salt, primary_password.as_bytes())?;
+    Ok(KeyStore { keys })
+}
+
+fn decrypt_login_value(key_store: &KeyStore, value: &str) -> Result<String> {
+    let data = base64::decode(value)
+        .context(Base64Error)
+        .context(DecryptError)?;
+    let (der, _) = Der::parse(&data)?;
+    let key_id = der.item(0)?.bytes()?;
+    let algorithm = der.item(1)?;
+    let oid = algorithm.item(0)?.bytes()?;
+    let iv = algorithm.item(1)?.bytes()?;
+    let ciphertext = der.item(2)?.bytes()?;
+
+    let key = key_store.keys.get(key_id).ok_or(Error::KeyMissing)?;
+    let plaintext = if oid == OID_DES_EDE3_CBC && key.len() >= 24 {
+        decrypt(Cipher::des_ede3_cbc(), &key[..24], Some(iv), ciphertext)
+    } else if oid == OID_AES256_CBC && key.len() >= 32 {
+        decrypt(Cipher::aes_256_cbc(), &key[..32], Some(iv), ciphertext)
+    } else {
+        return Err(Error::UnsupportedScheme);
+    }
+    .context(OpensslError)
+    .context(DecryptError)?;
+
+    String::from_utf8(plaintext).map_err(|_| Error::DecryptError {
+        source: LibraryError::IoError {
+            source: io::Error::new(io::ErrorKind::InvalidData, "expected UTF-8 text"),
+        },
+    })
+}
+
+/// Encrypt a value the same way as `reference`, reusing its key and scheme.
+fn encrypt_login_value(key_store: &KeyStore, reference: &str, value: &str) -> Result<String> {
+    let data = base64::decode(reference)
+        .context(Base64Error)
+        .context(EncryptError)?;
+    let (der, _) = Der::parse(&data)?;
+    let key_id = der.item(0)?.bytes()?;
+    let oid = der.item(1)?.item(0)?.bytes()?;
+    let key = key_store.keys.get(key_id).ok_or(Error::KeyMissing)?;
+
+    let (cipher, key, iv_len) = if oid == OID_DES_EDE3_CBC && key.len() >= 24 {
+        (Cipher::des_ede3_cbc(), &key[..24], 8)
+    } else if oid == OID_AES256_CBC && key.len() >= 32 {
+        (Cipher::aes_256_cbc(), &key[..32], 16)
+    } else {
+        return Err(Error::UnsupportedScheme);
+    };
+    let mut iv = vec![0u8; iv_len];
+    openssl::rand::rand_bytes(&mut iv)
+        .context(OpensslError)
+        .context(EncryptError)?;
+    let ciphertext = encrypt(cipher, key, Some(&iv), value.as_bytes())
+        .context(OpensslError)
+        .context(EncryptError)?;
+
+    let algorithm = der_encode(
+        TAG_SEQUENCE,
+        &[
+            der_encode(TAG_OBJECT_ID, oid),
+            der_encode(TAG_OCTET_STRING, &iv),
+        ]
+        .concat(),
+    );
+    let encoded = der_encode(
+        TAG_SEQUENCE,
+        &[
+            der_encode(TAG_OCTET_STRING, key_id),
+            algorithm,
+            der_encode(TAG_OCTET_STRING, &ciphertext),
+        ]
+        .concat(),
+    );
+    Ok(base64::encode(encoded))
+}
+
+fn parse_logins(profile: &Profile, key_store: &KeyStore) -> Result<(DB, Value)> {
+    let logins_file = profile.logins_file.to_string_lossy().into_owned();
+    let content = fs::read(&profile.logins_file)
+        .context(IoError)
+        .context(OpenFailed {
+            file: logins_file.to_owned(),
+        })?;
+    let logins: Value = serde_json::from_slice(&content)
+        .context(JsonError)
+        .context(OpenFailed {
+            file: logins_file.to_owned(),
+        })?;
+    let entries = logins
+        .get("logins")
+        .map(|l| l.as_array())
+        .flatten()
+        .ok_or(Error::LoginsWrongFormat { file: logins_file })?;
+
+    let mut db_vec = Vec::new();
+    for login in entries {
+        let field = |name: &str| login.get(name).map(|v| v.as_str()).flatten();
+        let (url, guid, encrypted_username, encrypted_password) = match (
+            field("hostname"),
+            field("guid"),
+            field("encryptedUsername"),
+            field("encryptedPassword"),
+        ) {
+            (Some(url), Some(guid), Some(username), Some(password)) => {
+                (url, guid, username, password)
+            }
+            _ => continue,
+        };
+
+        let username = match decrypt_login_value(key_store, encrypted_username) {
+            Ok(username) => username,
+            Err(err) => {
+                eprintln!("Warning: {}", err);
+                continue;
+            }
+        };
+        let password = match decrypt_login_value(key_store, encrypted_password) {
+            Ok(password) => password,
+            Err(err) => {
+                eprintln!("Warning: {}", err);
+                continue;
+            }
+        };
+        if username.is_empty() || password.is_empty() {
+            continue;
+        }
+
+        let mut db_entry = DBEntry::new(
+            url.to_owned(),
+            username,
+            password,
+            get_pw().context(UtilsError).context(LibError)?,
+        );
+        db_entry.uuid = Uuid::Firefox(guid.to_owned());
+        db_vec.push(db_entry);
+    }
+
+    Ok((DB::new(db_vec), logins))
+}
+
+fn write_logins(
+    profile: &Profile,
+    key_store: &KeyStore,
+    logins: &mut Value,
+    db: &DB,
+) -> Result<Vec<Uuid>> {
+    profile.check_browser_closed()?;
+    let logins_file = profile.logins_file.to_string_lossy().into_owned();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0);
+
+    let entries = logins
+        .get_mut("logins")
+        .map(|l| l.as_array_mut())
+        .flatten()
+        .ok_or(Error::LoginsWrongFormat {
+            file: logins_file.to_owned(),
+        })?;
+    let mut written = Vec::new();
+    for login in entries.iter_mut() {
+        let guid = login.get("guid").map(|v| v.as_str()).flatten();
+        let db_entry = match db.entries.iter().find(|e| {
+            guid.map(|g| e.uuid == Uuid::Firefox(g.to_owned()))
+                .unwrap_or(false)
+        }) {
+            Some(db_entry) => db_entry,
+            None => continue,
+        };
+        let reference = match login.get("encryptedPassword").map(|v| v.as_str()).flatten() {
+            Some(reference) => reference.to_owned(),
+            None => continue,
+        };
+
+        let encrypted_password =
+            encrypt_login_value(key_store, &reference, &db_entry.new_password)?;
+        login["encryptedPassword"] = Value::String(encrypted_password);
+        login["timePasswordChanged"] = Value::from(now);
+        written.push(db_entry.uuid.to_owned());
+    }
+
+    let content = serde_json::to_vec(logins)
+        .context(JsonError)
+        .context(DbUpdateFailed {
+            file: logins_file.to_owned(),
+        })?;
+    write_private_file(&profile.logins_file, &content)
+        .context(IoError)
+        .context(DbUpdateFailed {
+            file: logins_file.to_owned(),
+        })?;
+
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GLOBAL_SALT: &str = "0102030405060708090a0b0c0d0e0f1011121314";
+    /// "password-check" with PBKDF2-SHA256 and AES-256-CBC, password "primary"
+    const PBES2_ITEM: &str = "308182306e06092a864886f70d01050d3061304206092a864886f70d01050c3035\
+        04206465666768696a6b6c6d6e6f707172737475767778797a7b7c7d7e7f80818283020203e8020120300a\
+        06082a864886f70d0209301b060960864801650304012a040e32333435363738393a3b3c3d3e3f0410148e\
+        01fbc6cfbb6307a41acc7f63704d";
+    /// "password-check" with the legacy SHA1 and 3DES scheme, no password
+    const SHA1_3DES_ITEM: &str = "303c3028060b2a864886f70d010c05010330190414c8c9cacbcccdcecfd0d1\
+        d2d3d4d5d6d7d8d9dadb0201010410015694b1fe7ca218271094f12257a14a";
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn der_parses_nested_and_long_form_lengths() {
+        let long = vec![7u8; 300];
+        let data = der_encode(
+            TAG_SEQUENCE,
+            &[
+                der_encode(TAG_OCTET_STRING, &long),
+                der_encode(TAG_INTEGER, &[0x01, 0x00]),
+            ]
+            .concat(),
+        );
+        let (der, rest) = Der::parse(&data).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(der.item(0).unwrap().bytes().unwrap(), long.as_slice());
+        assert_eq!(der.item(1).unwrap().integer().unwrap(), 256);
+        assert!(der.item(2).is_err());
+    }
+
+    #[test]
+    fn der_rejects_truncated_data() {
+        let data = der_encode(TAG_OCTET_STRING, &[1, 2, 3, 4]);
+        assert!(matches!(
+            Der::parse(&data[..data.len() - 1]),
+            Err(Error::DerError)
+        ));
+        assert!(matches!(Der::parse(&[TAG_SEQUENCE]), Err(Error::DerError)));
+        assert!(matches!(
+            Der::parse(&[TAG_OCTET_STRING, 0x85, 0, 0, 0, 0, 1]),
+            Err(Error::DerError)
+        ));
+    }
+
+    #[test]
+    fn decrypt_pbe_pbes2() {
+        let item = unhex(PBES2_ITEM);
+        let salt = unhex(GLOBAL_SALT);
+        let value = decrypt_pbe(&item, &salt, b"primary").unwrap();
+        assert_eq!(value, PASSWORD_CHECK);
+        assert!(!matches!(
+            decrypt_pbe(&item, &salt, b"wrong"),
+            Ok(value) if value.starts_with(PASSWORD_CHECK)
+        ));
+    }
+
+    #[test]
+    fn decrypt_pbe_sha1_3des() {
+        let item = unhex(SHA1_3DES_ITEM);
+        let value = decrypt_pbe(&item, &unhex(GLOBAL_SALT), b"").unwrap();
+        assert_eq!(value, PASSWORD_CHECK);
+    }
+
+    #[test]
+    fn decrypt_pbe_rejects_unknown_schemes() {
+        let item = der_encode(
+            TAG_SEQUENCE,
+            &[
+                der_encode(TAG_SEQUENCE, &der_encode(TAG_OBJECT_ID, OID_AES256_CBC)),
+                der_encode(TAG_OCTET_STRING, &[0; 16]),
+            ]
+            .concat(),
+        );
+        assert!(matches!(
+            decrypt_pbe(&item, b"", b""),
+            Err(Error::UnsupportedScheme)
+        ));
+        assert!(matches!(
+            decrypt_pbe(&[0x30, 0x05, 0x04], b"", b""),
+            Err(Error::DerError)
+        ));
+    }
+
+    #[test]
+    fn login_values_roundtrip() {
+        let key_id = b"key-id".to_vec();
+        let mut keys = HashMap::new();
+        keys.insert(key_id.clone(), vec![0x42; 32]);
+        let key_store = KeyStore { keys };
+        let reference = base64::encode(der_encode(
+            TAG_SEQUENCE,
+            &[
+                der_encode(TAG_OCTET_STRING, &key_id),
+                der_encode(
+                    TAG_SEQUENCE,
+                    &[
+                        der_encode(TAG_OBJECT_ID, OID_AES256_CBC),
+                        der_encode(TAG_OCTET_STRING, &[0; 16]),
+                    ]
+                    .concat(),
+                ),
+                der_encode(TAG_OCTET_STRING, &[0; 16]),
+            ]
+            .concat(),
+        ));
+
+        let encrypted = encrypt_login_value(&key_store, &reference, "s3cret pass").unwrap();
+        assert_eq!(
+            decrypt_login_value(&key_store, &encrypted).unwrap(),
+            "s3cret pass"
+        );
+        let other_store = KeyStore {
+            keys: HashMap::new(),
+        };
+        assert!(matches!(
+            decrypt_login_value(&other_store, &encrypted),
+            Err(Error::KeyMissing)
+        ));
+    }
+}
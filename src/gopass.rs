@@ -0,0 +1,316 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::{fs, io, result};
+
+use snafu::{ResultExt, Snafu};
+
+use crate::config::{Configuration, Source};
+use crate::pass::{self, replace_password, split_content};
+use crate::utils::{self, Uuid};
+
+const GOPASS_BIN: &str = "gopass";
+const GOPASS_EXTENSION: &str = "gpg";
+const DEFAULT_CONFIG: &str = "gopass/config";
+const DEFAULT_ROOT_STORE: &str = "gopass/stores/root";
+
+#[derive(Debug, Snafu)]
+pub enum LibraryError {
+    IoError { source: io::Error },
+    PassError { source: pass::Error },
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not get path to the users config directory"))]
+    ConfigDirError,
+    #[snafu(display("Could not read gopass config \'{}\': {}", file, source))]
+    ConfigReadError {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not read gopass mount \'{}\': {}", mount, source))]
+    MountError {
+        mount: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not decrypt gopass secret \'{}\'", name))]
+    ShowError {
+        name: String,
+    },
+    #[snafu(display("Could not update gopass secret \'{}\'", name))]
+    InsertError {
+        name: String,
+    },
+    #[snafu(display("Entry has wrong uuid type"))]
+    WrongUuidType,
+    #[snafu(display(
+        "Gopass secret \'{}\' was modified during the update, keeping it untouched",
+        name
+    ))]
+    EntryChanged {
+        name: String,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// A gopass sub-store, the root store has an empty alias.
+#[derive(Debug)]
+struct Mount {
+    alias: String,
+    path: PathBuf,
+}
+
+pub fn run(config: &Configuration) {
+    let source = config.sources.first();
+    let mounts = match read_mounts(source) {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+
+    let mut entries = Vec::new();
+    for mount in &mounts {
+        match parse_mount(mount, source) {
+            Ok(mut db) => entries.append(&mut db.entries),
+            Err(err) => eprintln!("Warning: {}", err),
+        };
+    }
+    let db = utils::DB::new(entries);
+
+    // gopass encrypts to the recipients of each mount and commits by itself
+    let root = match mounts.first() {
+        Some(mount) => mount.path.to_owned(),
+        None => return,
+    };
+    pass::update_store(config, &root, &db, |_, db_entry| {
+        update_gopass_entry(db_entry)
+    });
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home_dir)) => home_dir.join(rest),
+        _ => PathBuf::from(path),
+    }
+}
+
+/// Read the mounts from the gopass config, which uses the git config format:
+/// `[mounts] path = ...` for the root store and `[mounts "alias"] path = ...`
+/// for every mounted sub-store.
+fn read_mounts(source: Option<&Source>) -> Result<Vec<Mount>> {
+    let config_path = match source {
+        Some(source) if !source.file.is_empty() => expand_home(&source.file),
+        _ => dirs::config_dir()
+            .ok_or(Error::ConfigDirError)?
+            .join(DEFAULT_CONFIG),
+    };
+    let content = match fs::read_to_string(&config_path) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(Error::ConfigReadError {
+                file: config_path.to_string_lossy().into_owned(),
+                source: LibraryError::IoError { source: err },
+            })
+        }
+    };
+
+    let mut mounts = Vec::new();
+    let mut section = None;
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            let header = line.trim_start_matches('[').trim_end_matches(']').trim();
+            section = if header == "mounts" {
+                Some(String::new())
+            } else {
+                header
+                    .strip_prefix("mounts ")
+                    .map(|alias| alias.trim().trim_matches('"').to_owned())
+            };
+            continue;
+        }
+
+        let (key, value) = match (&section, line.split_once('=')) {
+            (Some(_), Some(pair)) => pair,
+            _ => continue,
+        };
+        if key.trim() == "path" {
+            mounts.push(Mount {
+                alias: section.clone().unwrap_or_default(),
+                path: expand_home(value.trim().trim_matches('"')),
+            });
+        }
+    }
+
+    if !mounts.iter().any(|mount| mount.alias.is_empty()) {
+        let root = dirs::data_dir()
+            .ok_or(Error::ConfigDirError)?
+            .join(DEFAULT_ROOT_STORE);
+        mounts.push(Mount {
+            alias: String::new(),
+            path: root,
+        });
+    }
+    mounts.sort_by(|a, b| a.alias.cmp(&b.alias));
+
+    Ok(mounts)
+}
+
+fn secret_name(alias: &str, name: &str) -> String {
+    if alias.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}/{}", alias, name)
+    }
+}
+
+fn parse_mount(mount: &Mount, source: Option<&Source>) -> Result<utils::DB> {
+    let mut db = pass::parse_store(&mount.path, source, GOPASS_EXTENSION, |_, name| {
+        show_secret(&secret_name(&mount.alias, name))
+    })
+    .context(PassError)
+    .context(MountError {
+        mount: secret_name(&mount.alias, ""),
+    })?;
+
+    for entry in db.entries.iter_mut() {
+        if let Uuid::Pass(name) = &entry.uuid {
+            entry.uuid = Uuid::Pass(secret_name(&mount.alias, name));
+        }
+    }
+    Ok(db)
+}
+
+fn show_secret(name: &str) -> Result<String> {
+    let err = || Error::ShowError {
+        name: name.to_owned(),
+    };
+    let child = Command::new(GOPASS_BIN)
+        .args(&["show", "--noparsing", name])
+        .output()
+        .map_err(|_| err())?;
+    if !child.status.success() {
+        return Err(err());
+    }
+    String::from_utf8(child.stdout).map_err(|_| err())
+}
+
+/// Only the password line is replaced, YAML keys and other lines after it are
+/// written back unchanged.
+fn update_gopass_entry(db_entry: &utils::DBEntry) -> Result<String> {
+    let name = match &db_entry.uuid {
+        Uuid::Pass(name) => name.to_owned(),
+        _ => return Err(Error::WrongUuidType),
+    };
+
+    let content = show_secret(&name)?;
+    let (password, _) = split_content(&content);
+    if password.trim_end_matches('\r') != db_entry.old_password {
+        return Err(Error::EntryChanged { name });
+    }
+    let new_content = replace_password(&content, &db_entry.new_password);
+
+    let err = || Error::InsertError {
+        name: name.to_owned(),
+    };
+    let mut gopass = Command::new(GOPASS_BIN)
+        .args(&["insert", "--force", &name])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|_| err())?;
+    let written = match gopass.stdin.take() {
+        Some(mut stdin) => stdin.write_all(new_content.as_bytes()).is_ok(),
+        None => false,
+    };
+    let status = gopass.wait();
+    if !written || !status.map(|s| s.success()).unwrap_or(false) {
+        return Err(err());
+    }
+    println!(
+        "Updated password on website {}, with username {}",
+        db_entry.url, db_entry.username
+    );
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn mounts(config: &str) -> Vec<(String, PathBuf)> {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("config");
+        fs::write(&file, config).unwrap();
+        let source = Source::new(
+            "gopass".to_owned(),
+            file.to_string_lossy().into_owned(),
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            HashMap::new(),
+        );
+        read_mounts(Some(&source))
+            .unwrap()
+            .into_iter()
+            .map(|mount| (mount.alias, mount.path))
+            .collect()
+    }
+
+    #[test]
+    fn mounts_are_read_from_their_sections() {
+        let config = r#"
+[core]
+    autosync = true
+    path = /not/a/store
+# the root store
+[mounts]
+    path = /home/alice/.password-store
+[mounts "work"]
+    path = "/srv/stores/work"
+; nested below work
+[mounts "work/team"]
+    path=/srv/stores/team
+[recipients]
+    path = /not/a/store/either
+"#;
+        assert_eq!(
+            mounts(config),
+            vec![
+                (String::new(), PathBuf::from("/home/alice/.password-store")),
+                ("work".to_owned(), PathBuf::from("/srv/stores/work")),
+                ("work/team".to_owned(), PathBuf::from("/srv/stores/team")),
+            ]
+        );
+    }
+
+    #[test]
+    fn root_store_defaults_to_the_data_dir() {
+        let mounts = mounts("[mounts \"work\"]\n    path = ~/work\n");
+        assert_eq!(mounts.len(), 2);
+        assert_eq!(mounts[0].0, "");
+        assert!(mounts[0].1.ends_with(DEFAULT_ROOT_STORE));
+        assert_eq!(mounts[1].0, "work");
+        assert_eq!(mounts[1].1, expand_home("~/work"));
+    }
+
+    #[test]
+    fn secrets_of_mounts_are_prefixed_with_the_alias() {
+        assert_eq!(secret_name("", "github.com/alice"), "github.com/alice");
+        assert_eq!(
+            secret_name("work/team", "github.com/alice"),
+            "work/team/github.com/alice"
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use std::{io, result};
+
+use regex::Regex;
+use scraper::{Html, Selector};
+use snafu::{ResultExt, Snafu};
+use toml::Value;
+
+use crate::recipe::{self, parse_step_report, read_recipe, recipe_steps, run_steps};
+use crate::recipe::{step_field, substitute, StepReport};
+use crate::report::{parse_constraints, PasswordConstraints};
+use crate::utils::{DBEntry, RunOutput};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_REDIRECTS: u32 = 10;
+const VALUE_ATTRIBUTE: &str = "value";
+
+#[derive(Debug, Snafu)]
+pub enum LibraryError {
+    IoError { source: io::Error },
+    HttpError { source: Box<ureq::Error> },
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    RecipeError {
+        source: recipe::Error,
+    },
+    #[snafu(display(
+        "Step {} of recipe \'{}\' has an invalid pattern \'{}\'",
+        step,
+        path,
+        pattern
+    ))]
+    StepPatternWrong {
+        path: String,
+        step: usize,
+        pattern: String,
+    },
+    #[snafu(display("Request to \'{}\' failed: {}", url, source))]
+    RequestFailed {
+        url: String,
+        source: LibraryError,
+    },
+    #[snafu(display("\'{}\' answered with status {}", url, status))]
+    StatusError {
+        url: String,
+        status: u16,
+    },
+    #[snafu(display("There is no response to extract \'{}\' from", name))]
+    ResponseMissing {
+        name: String,
+    },
+    #[snafu(display("Could not extract \'{}\' from the response of \'{}\'", name, url))]
+    ExtractFailed {
+        name: String,
+        url: String,
+    },
+    #[snafu(display("The response of \'{}\' does not contain \'{}\'", url, text))]
+    TextMissing {
+        url: String,
+        text: String,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// Where the value of an `extract` step is taken from, either an attribute
+/// of the first element matching a CSS selector or the first capture group
+/// of a regex.
+#[derive(Debug)]
+pub enum Pattern {
+    Css {
+        selector: Selector,
+        attribute: String,
+    },
+    Regex(Regex),
+}
+
+#[derive(Debug)]
+pub enum HttpStep {
+    Get {
+        url: String,
+    },
+    Post {
+        url: String,
+        form: Vec<(String, String)>,
+    },
+    Extract {
+        name: String,
+        pattern: Pattern,
+    },
+    AssertText {
+        text: String,
+    },
+}
+
+/// A recipe with `runner = "http"`, for sites whose password change is a
+/// plain HTML form.
+#[derive(Debug)]
+pub struct HttpRecipe {
+    pub steps: Vec<HttpStep>,
+    pub reports: Vec<StepReport>,
+    pub constraints: Option<PasswordConstraints>,
+}
+
+/// The last response, after all redirects were followed.
+struct Page {
+    url: String,
+    body: String,
+}
+
+impl HttpRecipe {
+    /// Execute all steps with a fresh cookie jar.
+    pub fn run(&self, db_entry: &DBEntry, timeout: Duration) -> RunOutput {
+        let agent = ureq::AgentBuilder::new()
+            .timeout(REQUEST_TIMEOUT)
+            .redirects(MAX_REDIRECTS)
+            .build();
+        let mut vars = HashMap::new();
+        let mut page = None;
+        run_steps(
+            &self.steps,
+            &self.reports,
+            &self.constraints,
+            timeout,
+            |step| run_step(&agent, step, db_entry, &mut vars, &mut page),
+        )
+    }
+}
+
+fn run_step(
+    agent: &ureq::Agent,
+    step: &HttpStep,
+    db_entry: &DBEntry,
+    vars: &mut HashMap<String, String>,
+    page: &mut Option<Page>,
+) -> (String, Result<()>) {
+    match step {
+        HttpStep::Get { url } => {
+            let url = fill_in(url, db_entry, vars);
+            let result = request(agent, &url, None).map(|p| *page = Some(p));
+            (format!("get {}", url), result)
+        }
+        HttpStep::Post { url, form } => {
+            let url = fill_in(url, db_entry, vars);
+            let form: Vec<(String, String)> = form
+                .iter()
+                .map(|(k, v)| (k.to_owned(), fill_in(v, db_entry, vars)))
+                .collect();
+            let result = request(agent, &url, Some(&form)).map(|p| *page = Some(p));
+            (format!("post {}", url), result)
+        }
+        HttpStep::Extract { name, pattern } => {
+            let result = match page {
+                Some(page) => extract(page, pattern)
+                    .ok_or(Error::ExtractFailed {
+                        name: name.to_owned(),
+                        url: page.url.to_owned(),
+                    })
+                    .map(|value| {
+                        vars.insert(name.to_owned(), value);
+                    }),
+                None => Err(Error::ResponseMissing {
+                    name: name.to_owned(),
+                }),
+            };
+            (format!("extract {}", name), result)
+        }
+        HttpStep::AssertText { text } => {
+            let result = match page {
+                Some(page) if page.body.contains(text.as_str()) => Ok(()),
+                _ => Err(Error::TextMissing {
+                    url: page.as_ref().map(|p| p.url.to_owned()).unwrap_or_default(),
+                    text: text.to_owned(),
+                }),
+            };
+            (format!("assert_text \'{}\'", text), result)
+        }
+    }
+}
+
+/// Replace the placeholders of the entry and the extracted values, e.g.
+/// `{csrf}`.
+fn fill_in(value: &str, db_entry: &DBEntry, vars: &HashMap<String, String>) -> String {
+    let mut value = substitute(value, db_entry);
+    for (name, var) in vars {
+        value = value.replace(&format!("{{{}}}", name), var);
+    }
+    value
+}
+
+/// GET the URL or POST the form to it, redirects are followed and cookies
+/// are kept in the agent.
+fn request(agent: &ureq::Agent, url: &str, form: Option<&[(String, String)]>) -> Result<Page> {
+    let response = match form {
+        Some(form) => {
+            let form: Vec<(&str, &str)> =
+                form.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+            agent.post(url).send_form(&form)
+        }
+        None => agent.get(url).call(),
+    };
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(status, _)) => {
+            return Err(Error::StatusError {
+                url: url.to_owned(),
+                status,
+            })
+        }
+        Err(err) => {
+            return Err(Error::RequestFailed {
+                url: url.to_owned(),
+                source: LibraryError::HttpError {
+                    source: Box::new(err),
+                },
+            })
+        }
+    };
+
+    let final_url = response.get_url().to_owned();
+    let body = response
+        .into_string()
+        .context(IoError)
+        .context(RequestFailed { url })?;
+    Ok(Page {
+        url: final_url,
+        body,
+    })
+}
+
+fn extract(page: &Page, pattern: &Pattern) -> Option<String> {
+    match pattern {
+        Pattern::Css {
+            selector,
+            attribute,
+        } => Html::parse_document(&page.body)
+            .select(selector)
+            .next()
+            .map(|element| element.value().attr(attribute).map(|a| a.to_owned()))
+            .flatten(),
+        Pattern::Regex(re) => re
+            .captures(&page.body)
+            .map(|c| c.get(1).or_else(|| c.get(0)).map(|m| m.as_str().to_owned()))
+            .flatten(),
+    }
+}
+
+pub fn parse_http_recipe(path: &str) -> Result<HttpRecipe> {
+    let recipe = read_recipe(path).context(RecipeError)?;
+
+    let mut steps = Vec::new();
+    let mut reports = Vec::new();
+    for (i, step) in recipe_steps(&recipe, path)
+        .context(RecipeError)?
+        .iter()
+        .enumerate()
+    {
+        steps.push(parse_step(step, path, i + 1)?);
+        reports.push(parse_step_report(step, path, i + 1).context(RecipeError)?);
+    }
+
+    Ok(HttpRecipe {
+        steps,
+        reports,
+        constraints: parse_constraints(&recipe),
+    })
+}
+
+fn parse_step(step: &Value, path: &str, index: usize) -> Result<HttpStep> {
+    let field = |name: &'static str| step_field(step, path, index, name).context(RecipeError);
+    let pattern_err = |pattern: &str| Error::StepPatternWrong {
+        path: path.to_owned(),
+        step: index,
+        pattern: pattern.to_owned(),
+    };
+
+    if step.get("get").is_some() {
+        Ok(HttpStep::Get { url: field("get")? })
+    } else if step.get("post").is_some() {
+        let mut form = Vec::new();
+        if let Some(form_v) = step.get("form").map(|f| f.as_table()).flatten() {
+            for (k, e) in form_v {
+                let value = e.as_str().map(|v| v.to_owned());
+                form.push((k.to_owned(), value.unwrap_or_else(|| e.to_string())));
+            }
+        }
+        Ok(HttpStep::Post {
+            url: field("post")?,
+            form,
+        })
+    } else if step.get("extract").is_some() {
+        let pattern = if step.get("css").is_some() {
+            let css = field("css")?;
+            Pattern::Css {
+                selector: Selector::parse(&css).map_err(|_| pattern_err(&css))?,
+                attribute: field("attribute").unwrap_or_else(|_| VALUE_ATTRIBUTE.to_owned()),
+            }
+        } else {
+            let regex = field("regex")?;
+            Pattern::Regex(Regex::new(&regex).map_err(|_| pattern_err(&regex))?)
+        };
+        Ok(HttpStep::Extract {
+            name: field("extract")?,
+            pattern,
+        })
+    } else if step.get("assert_text").is_some() {
+        Ok(HttpStep::AssertText {
+            text: field("assert_text")?,
+        })
+    } else {
+        Err(Error::RecipeError {
+            source: recipe::Error::StepActionUnknown {
+                path: path.to_owned(),
+                step: index,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::{env, fs, process, thread};
+
+    use super::*;
+    use crate::report::{Outcome, Reason, Stage};
+
+    const CSRF: &str = "tok-42";
+    const SESSION: &str = "session=s1";
+    const OLD_PASSWORD: &str = "old pass";
+
+    const RECIPE: &str = r#"
+runner = "http"
+
+[[steps]]
+get = "{base}/login"
+
+[[steps]]
+extract = "csrf"
+css = "input[name=csrf]"
+
+[[steps]]
+post = "{base}/login"
+[steps.form]
+username = "{username}"
+password = "{old_password}"
+csrf = "{csrf}"
+
+[[steps]]
+assert_text = "Settings"
+stage = "logged_in"
+reason = "invalid_credentials"
+
+[[steps]]
+post = "{base}/settings/password"
+stage = "submitted"
+[steps.form]
+current = "{old_password}"
+new = "{new_password}"
+csrf = "{csrf}"
+
+[[steps]]
+assert_text = "has been changed"
+stage = "confirmed"
+reason = "password_rejected"
+
+[constraints]
+min_length = 8
+"#;
+
+    struct Request {
+        method: String,
+        path: String,
+        cookie: String,
+        form: HashMap<String, String>,
+    }
+
+    fn read_request(stream: &TcpStream) -> Option<Request> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_owned();
+        let path = parts.next()?.to_owned();
+
+        let mut cookie = String::new();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':')?;
+            match name.to_ascii_lowercase().as_str() {
+                "cookie" => cookie = value.trim().to_owned(),
+                "content-length" => content_length = value.trim().parse().ok()?,
+                _ => (),
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+        let form = url::form_urlencoded::parse(&body).into_owned().collect();
+        Some(Request {
+            method,
+            path,
+            cookie,
+            form,
+        })
+    }
+
+    /// A site with a login form protected by a CSRF token and a session
+    /// cookie, which rejects new passwords shorter than 8 characters.
+    fn respond(request: &Request) -> (&'static str, String, String) {
+        let logged_in = request.cookie.contains(SESSION);
+        let field = |name: &str| request.form.get(name).map(|v| v.as_str()).unwrap_or("");
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/login") => (
+                "200 OK",
+                format!("Set-Cookie: {}\r\n", SESSION),
+                format!(
+                    r#"<form><input type="hidden" name="csrf" value="{}"></form>"#,
+                    CSRF
+                ),
+            ),
+            ("POST", "/login")
+                if logged_in
+                    && field("csrf") == CSRF
+                    && field("username") == "alice"
+                    && field("password") == OLD_PASSWORD =>
+            {
+                (
+                    "302 Found",
+                    "Location: /settings\r\n".to_owned(),
+                    String::new(),
+                )
+            }
+            ("POST", "/login") => ("200 OK", String::new(), "Invalid login".to_owned()),
+            ("GET", "/settings") if logged_in => {
+                ("200 OK", String::new(), "<h1>Settings</h1>".to_owned())
+            }
+            ("POST", "/settings/password")
+                if logged_in && field("csrf") == CSRF && field("current") == OLD_PASSWORD =>
+            {
+                let body = if field("new").len() >= 8 {
+                    "Your password has been changed"
+                } else {
+                    "Password is too short"
+                };
+                ("200 OK", String::new(), body.to_owned())
+            }
+            _ => ("403 Forbidden", String::new(), String::new()),
+        }
+    }
+
+    fn mock_site() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for mut stream in listener.incoming().filter_map(|s| s.ok()) {
+                let request = match read_request(&stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let (status, headers, body) = respond(&request);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    headers,
+                    body.len(),
+                    body
+                );
+            }
+        });
+        base
+    }
+
+    fn run_recipe(old_password: &str, new_password: &str) -> RunOutput {
+        let base = mock_site();
+        let path = env::temp_dir().join(format!(
+            "passup-http-test-{}-{}.toml",
+            process::id(),
+            base.rsplit(':').next().unwrap()
+        ));
+        fs::write(&path, RECIPE.replace("{base}", &base)).unwrap();
+        let recipe = parse_http_recipe(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let db_entry = DBEntry::new(
+            base,
+            "alice".to_owned(),
+            old_password.to_owned(),
+            new_password.to_owned(),
+        );
+        recipe.unwrap().run(&db_entry, Duration::from_secs(60))
+    }
+
+    #[test]
+    fn password_change_is_confirmed() {
+        let output = run_recipe(OLD_PASSWORD, "new password");
+        assert!(output.success, "{}", output.log);
+        assert_eq!(output.report.stage, Some(Stage::Confirmed));
+        assert_eq!(output.report.constraints.unwrap().min_length, Some(8));
+    }
+
+    #[test]
+    fn wrong_password_fails_before_login() {
+        let output = run_recipe("wrong", "new password");
+        assert!(!output.success);
+        assert_eq!(output.report.stage, None);
+        assert_eq!(output.report.reason, Some(Reason::InvalidCredentials));
+        assert_eq!(output.outcome(), Outcome::Failed);
+    }
+
+    #[test]
+    fn rejected_password_is_submitted_but_not_confirmed() {
+        let output = run_recipe(OLD_PASSWORD, "short");
+        assert!(!output.success);
+        assert_eq!(output.report.stage, Some(Stage::Submitted));
+        assert_eq!(output.report.reason, Some(Reason::PasswordRejected));
+        assert!(output.log.contains("has been changed"), "{}", output.log);
+    }
+}
@@ -6,7 +6,7 @@ use rpassword::read_password;
 use snafu::{ResultExt, Snafu};
 
 use crate::config::{Configuration, Source};
-use crate::utils::{self, get_pw, run_update_threads, DBEntry, Uuid, DB};
+use crate::utils::{self, commit_results, get_pw, run_update_threads, DBEntry, Uuid, DB};
 
 #[derive(Debug, Snafu)]
 enum LibraryError {
@@ -66,20 +66,12 @@ pub fn run(config: &Configuration) {
         };
 
         let (tx, rx) = channel();
-        let nr_jobs = run_update_threads(&db, &source.blocklist, config, tx);
+        run_update_threads(&db, &source.blocklist, config, tx);
 
-        let thread_results = rx.iter().take(nr_jobs);
-        for thread_result in thread_results {
-            let output = match thread_result.result {
-                Ok(output) => output,
-                Err(err) => {
-                    eprintln!("Error while executing Nightwatch: {}", err);
-                    continue;
-                }
-            };
-
-            let db_entry = thread_result.db_entry;
-            if output.status.success() {
+        // The whole database is encrypted again on every write
+        commit_results(rx, true, |updated_db| {
+            let mut written = Vec::new();
+            for db_entry in &updated_db.entries {
                 let mut new_entry = Entry::new();
                 new_entry.set_url(&db_entry.url);
                 new_entry.set_username(&db_entry.username);
@@ -91,29 +83,14 @@ pub fn run(config: &Configuration) {
                         continue;
                     }
                 };
-                kpdb_db.root_group.remove_entry(uuid);
+                if kpdb_db.root_group.remove_entry(uuid).is_none() {
+                    continue;
+                }
                 kpdb_db.root_group.add_entry(new_entry);
-                println!(
-                    "Updated password on website {}, with username {}",
-                    db_entry.url, db_entry.username
-                );
-            } else {
-                let db_entry_ = db_entry.clone();
-                let err = utils::Error::NightwatchExecError {
-                    db_entry: db_entry_,
-                    output,
-                };
-                eprintln!("{}", err);
-                continue;
-            }
-        }
-        match write_db(source, &kpdb_db) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                print_db_content(&kpdb_db);
+                written.push(db_entry.uuid.to_owned());
             }
-        };
+            write_db(source, &kpdb_db).map(|_| written)
+        });
     }
 }
 
@@ -215,34 +192,20 @@ fn find_entry(entries: &[DBEntry], uuid: String) -> Result<&DBEntry> {
     Err(Error::EntryReference)
 }
 
-fn print_db_content(db: &Database) {
-    println!("DB Content:");
-    let mut entries = db.root_group.entries.clone();
-    for entry in entries.iter_mut() {
-        let db_entry = match parse_db_entry(entry) {
-            Ok(entry) => entry,
-            Err(_) => continue,
-        };
-        println!(
-            "{}, {}, {}, {}",
-            db_entry.url, db_entry.username, db_entry.old_password, db_entry.new_password
-        );
-    }
-}
-
+/// Save to a temporary file next to the database and move it over the
+/// database, so a failed write leaves the last saved state intact.
 fn write_db(source: &Source, db: &Database) -> Result<()> {
     let err = DbUpdateFailed {
         file: source.file.to_owned(),
     };
-    fs::remove_file(&source.file)
+    let tmp_file = format!("{}.tmp", &source.file);
+    let mut file = fs::File::create(&tmp_file)
         .context(IoError)
         .context(err.clone())?;
-    let mut file = fs::File::create(&source.file)
+    db.save(&mut file).context(KpdbError).context(err.clone())?;
+    fs::rename(&tmp_file, &source.file)
         .context(IoError)
-        .context(err.clone())?;
-    db.save(&mut file).context(KpdbError).context(err)?;
-
-    println!("Finished with {}!", &source.file);
+        .context(err)?;
     Ok(())
 }
 
@@ -1,9 +1,39 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{result, string};
 
 use cryptex::keyring::linux::LinuxOsKeyRing as OsKeyRing;
 use cryptex::{self, KeyRing};
+use dbus::arg::{RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use dbus::message::MatchRule;
+use dbus::Path;
 use snafu::{ResultExt, Snafu};
 
+const KWALLET_INTERFACE: &str = "org.kde.KWallet";
+const KWALLET_SERVICES: [(&str, &str); 2] = [
+    ("org.kde.kwalletd6", "/modules/kwalletd6"),
+    ("org.kde.kwalletd5", "/modules/kwalletd5"),
+];
+const KWALLET_APP_ID: &str = "PassUp";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(60);
+
+const SECRETS_SERVICE: &str = "org.freedesktop.secrets";
+const SECRETS_PATH: &str = "/org/freedesktop/secrets";
+const SERVICE_INTERFACE: &str = "org.freedesktop.Secret.Service";
+const COLLECTION_INTERFACE: &str = "org.freedesktop.Secret.Collection";
+const ITEM_INTERFACE: &str = "org.freedesktop.Secret.Item";
+const PROMPT_INTERFACE: &str = "org.freedesktop.Secret.Prompt";
+const SECRET_ALGORITHM: &str = "plain";
+const NO_PROMPT: &str = "/";
+const PROMPT_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A secret as transferred by the Secret Service API: session, parameters,
+/// value and content type.
+type Secret = (Path<'static>, Vec<u8>, Vec<u8>, String);
+
 #[derive(Debug, Snafu)]
 pub enum LibraryError {
     KeyError {
@@ -12,30 +42,62 @@ pub enum LibraryError {
     Utf8Error {
         source: string::FromUtf8Error,
     },
+    DbusError {
+        source: dbus::Error,
+    },
 }
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Could not find password for Chrome/Chromium"))]
-    PasswordNotPresent,
+    #[snafu(display("Could not find the Safe Storage password for {}", application))]
+    PasswordNotPresent {
+        application: String,
+    },
     CryptexError {
         source: LibraryError,
     },
     StringConversionError {
         source: LibraryError,
     },
+    #[snafu(display("Could not reach KWallet over D-Bus: {}", source))]
+    KWalletError {
+        source: LibraryError,
+    },
+    #[snafu(display("KWallet \'{}\' could not be opened", wallet))]
+    KWalletOpenFailed {
+        wallet: String,
+    },
+    #[snafu(display("Could not find \'{}\' in the KWallet folder \'{}\'", key, folder))]
+    KWalletEntryMissing {
+        folder: String,
+        key: String,
+    },
+    #[snafu(display("Could not reach the Secret Service over D-Bus: {}", source))]
+    SecretServiceError {
+        source: LibraryError,
+    },
+    #[snafu(display("Unlocking the Secret Service collections was dismissed"))]
+    UnlockDismissed,
+    #[snafu(display("Could not access secret \'{}\': {}", item, source))]
+    SecretError {
+        item: String,
+        source: LibraryError,
+    },
 }
 
 type Result<T, E = Error> = result::Result<T, E>;
 
-pub fn get_chrome_password() -> Result<String> {
+/// Look up the Safe Storage secret of a Chromium based browser, which is
+/// stored with its application name, e.g. "chrome", "chromium" or "brave".
+pub fn get_chrome_password(application: &str) -> Result<String> {
     let secrets = OsKeyRing::peek_secret("")
         .context(KeyError)
         .context(CryptexError)?;
     let mut password = String::new();
+    let attribute = format!("\"application\": \"{}\"", application);
 
     for s in secrets {
-        if s.0.contains("application\": \"chrome") || s.0.contains("application\": \"chromium") {
+        if s.0.contains(&attribute) {
             password = String::from_utf8(s.1.as_slice().to_vec())
                 .context(Utf8Error)
                 .context(StringConversionError)?;
@@ -44,8 +106,523 @@ pub fn get_chrome_password() -> Result<String> {
     }
 
     if password.is_empty() {
-        return Err(Error::PasswordNotPresent);
+        return Err(Error::PasswordNotPresent {
+            application: application.to_owned(),
+        });
     }
 
     Ok(password)
 }
+
+/// Read a password from the network wallet of KWallet over the D-Bus session
+/// bus, e.g. "Chrome Safe Storage" from the folder "Chrome Keys".
+pub fn get_kwallet_password(folder: &str, key: &str) -> Result<String> {
+    let connection = Connection::new_session()
+        .context(DbusError)
+        .context(KWalletError)?;
+
+    let mut last_err = None;
+    for (service, path) in KWALLET_SERVICES.iter() {
+        let proxy = connection.with_proxy(*service, *path, DBUS_TIMEOUT);
+        let wallet: String = match proxy.method_call(KWALLET_INTERFACE, "networkWallet", ()) {
+            Ok((wallet,)) => wallet,
+            Err(err) => {
+                last_err = Some(err);
+                continue;
+            }
+        };
+
+        let (handle,): (i32,) = proxy
+            .method_call(
+                KWALLET_INTERFACE,
+                "open",
+                (wallet.as_str(), 0i64, KWALLET_APP_ID),
+            )
+            .context(DbusError)
+            .context(KWalletError)?;
+        if handle < 0 {
+            return Err(Error::KWalletOpenFailed { wallet });
+        }
+
+        let password = read_kwallet_entry(&proxy, handle, folder, key);
+        let _: result::Result<(i32,), dbus::Error> =
+            proxy.method_call(KWALLET_INTERFACE, "close", (handle, false, KWALLET_APP_ID));
+        return password;
+    }
+
+    Err(Error::KWalletError {
+        source: LibraryError::DbusError {
+            source: last_err
+                .unwrap_or_else(|| dbus::Error::new_failed("No KWallet service available")),
+        },
+    })
+}
+
+/// `readPassword` returns an empty string for a missing entry, so only an
+/// entry which `hasEntry` does not know counts as missing.
+fn read_kwallet_entry(
+    proxy: &dbus::blocking::Proxy<&Connection>,
+    handle: i32,
+    folder: &str,
+    key: &str,
+) -> Result<String> {
+    let (exists,): (bool,) = proxy
+        .method_call(
+            KWALLET_INTERFACE,
+            "hasEntry",
+            (handle, folder, key, KWALLET_APP_ID),
+        )
+        .context(DbusError)
+        .context(KWalletError)?;
+    if !exists {
+        return Err(Error::KWalletEntryMissing {
+            folder: folder.to_owned(),
+            key: key.to_owned(),
+        });
+    }
+    let (password,): (String,) = proxy
+        .method_call(
+            KWALLET_INTERFACE,
+            "readPassword",
+            (handle, folder, key, KWALLET_APP_ID),
+        )
+        .context(DbusError)
+        .context(KWalletError)?;
+    Ok(password)
+}
+
+/// An item of the Secret Service, identified by its D-Bus object path.
+#[derive(Debug)]
+pub struct SecretItem {
+    pub path: String,
+    pub label: String,
+    pub attributes: HashMap<String, String>,
+    pub secret: String,
+}
+
+/// A session with the Secret Service on the D-Bus session bus, e.g. GNOME
+/// Keyring or KeePassXC. Secrets are transferred with the plain algorithm.
+pub struct SecretService {
+    connection: Connection,
+    session: Path<'static>,
+}
+
+impl SecretService {
+    pub fn connect() -> Result<Self> {
+        let connection = Connection::new_session()
+            .context(DbusError)
+            .context(SecretServiceError)?;
+        let proxy = connection.with_proxy(SECRETS_SERVICE, SECRETS_PATH, DBUS_TIMEOUT);
+        let (_, session): (Variant<Box<dyn RefArg>>, Path<'static>) = proxy
+            .method_call(
+                SERVICE_INTERFACE,
+                "OpenSession",
+                (SECRET_ALGORITHM, Variant("")),
+            )
+            .context(DbusError)
+            .context(SecretServiceError)?;
+        Ok(Self {
+            connection,
+            session,
+        })
+    }
+
+    /// Unlock all collections and return their items with secrets.
+    pub fn items(&self) -> Result<Vec<SecretItem>> {
+        let service = self
+            .connection
+            .with_proxy(SECRETS_SERVICE, SECRETS_PATH, DBUS_TIMEOUT);
+        let collections: Vec<Path<'static>> = service
+            .get(SERVICE_INTERFACE, "Collections")
+            .context(DbusError)
+            .context(SecretServiceError)?;
+        self.unlock(collections.to_owned())?;
+
+        let mut items = Vec::new();
+        for collection in collections {
+            let proxy = self
+                .connection
+                .with_proxy(SECRETS_SERVICE, collection, DBUS_TIMEOUT);
+            let paths: Vec<Path<'static>> = proxy
+                .get(COLLECTION_INTERFACE, "Items")
+                .context(DbusError)
+                .context(SecretServiceError)?;
+            for path in paths {
+                let proxy =
+                    self.connection
+                        .with_proxy(SECRETS_SERVICE, path.to_owned(), DBUS_TIMEOUT);
+                let item_err = || SecretError {
+                    item: path.to_string(),
+                };
+                let attributes: HashMap<String, String> = proxy
+                    .get(ITEM_INTERFACE, "Attributes")
+                    .context(DbusError)
+                    .context(item_err())?;
+                let label: String = proxy
+                    .get(ITEM_INTERFACE, "Label")
+                    .context(DbusError)
+                    .context(item_err())?;
+                items.push(SecretItem {
+                    path: path.to_string(),
+                    label,
+                    attributes,
+                    secret: self.get_secret(&path)?,
+                });
+            }
+        }
+        Ok(items)
+    }
+
+    pub fn get_secret(&self, item: &str) -> Result<String> {
+        let proxy = self
+            .connection
+            .with_proxy(SECRETS_SERVICE, item, DBUS_TIMEOUT);
+        let item_err = || SecretError {
+            item: item.to_owned(),
+        };
+        let (secret,): (Secret,) = proxy
+            .method_call(ITEM_INTERFACE, "GetSecret", (self.session.to_owned(),))
+            .context(DbusError)
+            .context(item_err())?;
+        String::from_utf8(secret.2)
+            .context(Utf8Error)
+            .context(item_err())
+    }
+
+    pub fn set_secret(&self, item: &str, value: &str) -> Result<()> {
+        let proxy = self
+            .connection
+            .with_proxy(SECRETS_SERVICE, item, DBUS_TIMEOUT);
+        let secret: Secret = (
+            self.session.to_owned(),
+            Vec::new(),
+            value.as_bytes().to_vec(),
+            "text/plain".to_owned(),
+        );
+        proxy
+            .method_call::<(), _, _, _>(ITEM_INTERFACE, "SetSecret", (secret,))
+            .context(DbusError)
+            .context(SecretError {
+                item: item.to_owned(),
+            })
+    }
+
+    /// Unlock the objects, if the service needs a prompt for that, e.g. for
+    /// the login password, wait until the user completed it.
+    fn unlock(&self, objects: Vec<Path<'static>>) -> Result<()> {
+        let service = self
+            .connection
+            .with_proxy(SECRETS_SERVICE, SECRETS_PATH, DBUS_TIMEOUT);
+        let (_, prompt): (Vec<Path<'static>>, Path<'static>) = service
+            .method_call(SERVICE_INTERFACE, "Unlock", (objects,))
+            .context(DbusError)
+            .context(SecretServiceError)?;
+        if &*prompt == NO_PROMPT {
+            return Ok(());
+        }
+
+        let dismissed = Arc::new(Mutex::new(None));
+        let dismissed_ = dismissed.clone();
+        let rule =
+            MatchRule::new_signal(PROMPT_INTERFACE, "Completed").with_path(prompt.to_owned());
+        self.connection
+            .add_match(
+                rule,
+                move |(dismissed,): (bool,), _: &Connection, _: &dbus::Message| {
+                    *dismissed_.lock().unwrap() = Some(dismissed);
+                    false
+                },
+            )
+            .context(DbusError)
+            .context(SecretServiceError)?;
+        self.connection
+            .with_proxy(SECRETS_SERVICE, prompt, DBUS_TIMEOUT)
+            .method_call::<(), _, _, _>(PROMPT_INTERFACE, "Prompt", ("",))
+            .context(DbusError)
+            .context(SecretServiceError)?;
+
+        let mut waited = Duration::from_secs(0);
+        while dismissed.lock().unwrap().is_none() && waited < PROMPT_TIMEOUT {
+            self.connection
+                .process(Duration::from_secs(1))
+                .context(DbusError)
+                .context(SecretServiceError)?;
+            waited += Duration::from_secs(1);
+        }
+        let dismissed = *dismissed.lock().unwrap();
+        match dismissed {
+            Some(false) => Ok(()),
+            _ => Err(Error::UnlockDismissed),
+        }
+    }
+}
+
+/// A private D-Bus session bus for tests, services on it are answered by a
+/// handler instead of a real KWallet or Secret Service.
+#[cfg(test)]
+pub mod mock {
+    use std::collections::HashMap;
+    use std::ffi::CString;
+    use std::io::{BufRead, BufReader};
+    use std::path::PathBuf;
+    use std::process::{self, Child, Command, Stdio};
+    use std::sync::mpsc::channel;
+    use std::sync::{Arc, Mutex, MutexGuard};
+    use std::thread;
+    use std::time::Duration;
+    use std::{env, fs};
+
+    use dbus::arg::Variant;
+    use dbus::blocking::Connection;
+    use dbus::channel::{Channel, MatchingReceiver, Sender};
+    use dbus::message::MatchRule;
+    use dbus::strings::ErrorName;
+    use dbus::{Message, Path};
+
+    use super::{Secret, KWALLET_SERVICES, NO_PROMPT, SECRETS_PATH, SECRETS_SERVICE};
+
+    const COLLECTION_PATH: &str = "/org/freedesktop/secrets/collection/login";
+    const SESSION_PATH: &str = "/org/freedesktop/secrets/session/1";
+
+    /// An item of the mocked Secret Service.
+    pub struct MockItem {
+        pub label: &'static str,
+        pub attributes: Vec<(&'static str, &'static str)>,
+        pub secret: &'static str,
+    }
+
+    /// libdbus reads the session bus address only once per process, so all
+    /// tests share one socket path and only one of them may use it at a time.
+    static BUS_LOCK: Mutex<()> = Mutex::new(());
+
+    pub struct MockBus {
+        daemon: Child,
+        socket: PathBuf,
+        address: String,
+        _guard: MutexGuard<'static, ()>,
+    }
+
+    impl MockBus {
+        /// Start a session bus, `None` if `dbus-daemon` is not installed.
+        pub fn start() -> Option<Self> {
+            let guard = BUS_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+            let socket = env::temp_dir().join(format!("passup-test-bus-{}", process::id()));
+            let _ = fs::remove_file(&socket);
+            let address = format!("unix:path={}", socket.to_string_lossy());
+            let mut daemon = Command::new("dbus-daemon")
+                .args(&["--session", "--nofork", "--print-address"])
+                .arg(format!("--address={}", address))
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            // The address is printed once the daemon listens
+            BufReader::new(daemon.stdout.take()?)
+                .read_line(&mut String::new())
+                .ok()?;
+            env::set_var("DBUS_SESSION_BUS_ADDRESS", &address);
+            Some(Self {
+                daemon,
+                socket,
+                address,
+                _guard: guard,
+            })
+        }
+
+        /// Own `name` and answer its method calls with `handler`, calls it
+        /// returns `None` for fail with `UnknownMethod`.
+        pub fn serve<F>(&self, name: &'static str, mut handler: F)
+        where
+            F: FnMut(&Message) -> Option<Message> + Send + 'static,
+        {
+            let address = self.address.to_owned();
+            let (ready_tx, ready_rx) = channel();
+            thread::spawn(move || {
+                let mut channel = Channel::open_private(&address).unwrap();
+                channel.register().unwrap();
+                let connection = Connection::from(channel);
+                connection.request_name(name, false, true, true).unwrap();
+                connection.start_receive(
+                    MatchRule::new_method_call(),
+                    Box::new(move |message, connection| {
+                        let reply = handler(&message).unwrap_or_else(|| {
+                            message.error(
+                                &ErrorName::new("org.freedesktop.DBus.Error.UnknownMethod")
+                                    .unwrap(),
+                                &CString::new("not mocked").unwrap(),
+                            )
+                        });
+                        let _ = connection.send(reply);
+                        true
+                    }),
+                );
+                ready_tx.send(()).unwrap();
+                while connection.process(Duration::from_millis(100)).is_ok() {}
+            });
+            ready_rx.recv().unwrap();
+        }
+    }
+
+    /// KWallet with a single entry, `open` returns `handle`.
+    pub fn kwallet(bus: &MockBus, handle: i32, folder: &str, key: &str, password: &str) {
+        let entry = (folder.to_owned(), key.to_owned());
+        let password = password.to_owned();
+        bus.serve(KWALLET_SERVICES[0].0, move |message| {
+            let member = message.member()?;
+            match &*member {
+                "networkWallet" => Some(message.method_return().append1("kdewallet")),
+                "open" => Some(message.method_return().append1(handle)),
+                "hasEntry" => {
+                    let (_, folder, key): (i32, String, String) = message.read3().ok()?;
+                    Some(message.method_return().append1((folder, key) == entry))
+                }
+                "readPassword" => {
+                    let (_, folder, key): (i32, String, String) = message.read3().ok()?;
+                    let value = if (folder, key) == entry {
+                        password.as_str()
+                    } else {
+                        ""
+                    };
+                    Some(message.method_return().append1(value))
+                }
+                "close" => Some(message.method_return().append1(0i32)),
+                _ => None,
+            }
+        });
+    }
+
+    /// Secret Service with one unlocked collection, which holds the items as
+    /// `<collection>/1`, `<collection>/2`, ... The returned map holds the
+    /// current secret of every item path.
+    pub fn secret_service(
+        bus: &MockBus,
+        items: Vec<MockItem>,
+    ) -> Arc<Mutex<HashMap<String, String>>> {
+        let paths: Vec<String> = (1..=items.len())
+            .map(|i| format!("{}/{}", COLLECTION_PATH, i))
+            .collect();
+        let secrets = Arc::new(Mutex::new(
+            paths
+                .iter()
+                .zip(items.iter())
+                .map(|(path, item)| (path.to_owned(), item.secret.to_owned()))
+                .collect::<HashMap<_, _>>(),
+        ));
+        let secrets_ = secrets.clone();
+        bus.serve(SECRETS_SERVICE, move |message| {
+            let path = message.path()?.to_string();
+            let item = paths.iter().position(|p| *p == path).map(|i| &items[i]);
+            let object_paths = |values: &[String]| {
+                values
+                    .iter()
+                    .map(|p| Path::new(p.to_owned()).unwrap())
+                    .collect::<Vec<_>>()
+            };
+            match &*message.member()? {
+                "OpenSession" => Some(
+                    message
+                        .method_return()
+                        .append2(Variant(""), Path::new(SESSION_PATH).unwrap()),
+                ),
+                "Unlock" => Some(
+                    message
+                        .method_return()
+                        .append2(Vec::<Path>::new(), Path::new(NO_PROMPT).unwrap()),
+                ),
+                "Get" => {
+                    let (_, property): (String, String) = message.read2().ok()?;
+                    let reply = message.method_return();
+                    match (path.as_str(), property.as_str(), item) {
+                        (SECRETS_PATH, "Collections", _) => Some(
+                            reply.append1(Variant(object_paths(&[COLLECTION_PATH.to_owned()]))),
+                        ),
+                        (COLLECTION_PATH, "Items", _) => {
+                            Some(reply.append1(Variant(object_paths(&paths))))
+                        }
+                        (_, "Label", Some(item)) => Some(reply.append1(Variant(item.label))),
+                        (_, "Attributes", Some(item)) => Some(
+                            reply.append1(Variant(
+                                item.attributes
+                                    .iter()
+                                    .map(|(k, v)| (k.to_string(), v.to_string()))
+                                    .collect::<HashMap<_, _>>(),
+                            )),
+                        ),
+                        _ => None,
+                    }
+                }
+                "GetSecret" => {
+                    let value = secrets_.lock().unwrap().get(&path)?.to_owned();
+                    let secret: Secret = (
+                        Path::new(SESSION_PATH).unwrap(),
+                        Vec::new(),
+                        value.into_bytes(),
+                        "text/plain".to_owned(),
+                    );
+                    Some(message.method_return().append1(secret))
+                }
+                "SetSecret" => {
+                    let (secret,): (Secret,) = message.read1().map(|s| (s,)).ok()?;
+                    let value = String::from_utf8(secret.2).ok()?;
+                    secrets_.lock().unwrap().insert(path, value);
+                    Some(message.method_return())
+                }
+                _ => None,
+            }
+        });
+        secrets
+    }
+
+    impl Drop for MockBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+            let _ = fs::remove_file(&self.socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{self, MockBus};
+    use super::*;
+
+    #[test]
+    fn kwallet_password_is_read() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, 1, "Chrome Keys", "Chrome Safe Storage", "s3cret");
+        assert_eq!(
+            get_kwallet_password("Chrome Keys", "Chrome Safe Storage").unwrap(),
+            "s3cret"
+        );
+    }
+
+    #[test]
+    fn kwallet_missing_entry_is_told_apart() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, 1, "Chrome Keys", "Chrome Safe Storage", "s3cret");
+        assert!(matches!(
+            get_kwallet_password("Brave Keys", "Brave Safe Storage"),
+            Err(Error::KWalletEntryMissing { .. })
+        ));
+    }
+
+    #[test]
+    fn kwallet_refused_open_is_an_error() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::kwallet(&bus, -1, "Chrome Keys", "Chrome Safe Storage", "s3cret");
+        assert!(matches!(
+            get_kwallet_password("Chrome Keys", "Chrome Safe Storage"),
+            Err(Error::KWalletOpenFailed { .. })
+        ));
+    }
+}
@@ -1,20 +1,33 @@
+mod bitwarden;
 mod chrome;
 mod config;
+mod credfile;
+mod csv;
+mod firefox;
+mod gopass;
+mod http;
 mod kdbx;
 mod keyring;
+mod onepassword;
 mod pass;
+mod passage;
 mod pwsafe;
+mod recipe;
+mod report;
+mod runner;
+mod schedule;
+mod secretservice;
 mod utils;
+mod webdriver;
 
-use clap::{App, Arg};
-use config::{parse_config, ProfileTypes};
-use utils::check_dependencies;
+use clap::{App, Arg, SubCommand};
+use config::{parse_config, Mode, ProfileTypes};
 
 fn main() {
     let matches = App::new("PassUp")
         .version("0.1")
         .author("Gabriel V. <gabriel.vukovic@student.tugraz.com>")
-        .about("Automatically updates password databases of keepass, pass, PasswordSafe and Chrome")
+        .about("Automatically updates password databases of password managers, browsers, password manager exports and credential files")
         .arg(
             Arg::with_name("config")
                 .short("c")
@@ -24,11 +37,19 @@ fn main() {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("headed")
+                .long("headed")
+                .help("Shows the browser instead of running it headless, e.g. to debug a script"),
+        )
+        .subcommand(SubCommand::with_name("check").about(
+            "Logs in with the stored passwords and reports which credentials are valid, without changing anything",
+        ))
         .get_matches();
 
     let config_path = matches.value_of("config").unwrap_or("config.toml");
 
-    let config = match parse_config(config_path) {
+    let mut config = match parse_config(config_path) {
         Ok(config) => config,
         Err(err) => {
             eprintln!("Configuration Error: {}", err);
@@ -36,20 +57,40 @@ fn main() {
         }
     };
 
-    if let Err(err) = check_dependencies(&config) {
-        eprintln!("Dependency Error: {}", err);
-        return;
-    };
+    if matches.is_present("headed") {
+        config.browser.headless = false;
+    }
+    if matches.subcommand_matches("check").is_some() {
+        config.mode = Mode::Check;
+    }
 
     if config.profile.ptype.eq(&ProfileTypes::Kdbx) {
         kdbx::run(&config);
     } else if config.profile.ptype.eq(&ProfileTypes::Pass) {
         pass::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::Gopass) {
+        gopass::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::Passage) {
+        passage::run(&config);
     } else if config.profile.ptype.eq(&ProfileTypes::Pwsafe) {
         pwsafe::run(&config);
     } else if config.profile.ptype.eq(&ProfileTypes::ChromeG)
         || config.profile.ptype.eq(&ProfileTypes::ChromeK)
     {
         chrome::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::Firefox) {
+        firefox::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::BitwardenJson) {
+        bitwarden::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::Csv) {
+        csv::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::OnePassword) {
+        onepassword::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::Netrc)
+        || config.profile.ptype.eq(&ProfileTypes::GitCredentials)
+    {
+        credfile::run(&config);
+    } else if config.profile.ptype.eq(&ProfileTypes::SecretService) {
+        secretservice::run(&config);
     }
 }
@@ -0,0 +1,453 @@
+use std::fs::File;
+use std::io::{Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{io, result};
+
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::config::{Configuration, Source};
+use crate::utils::{
+    self, commit_results, get_pw, run_update_threads, write_private_file, DBEntry, Uuid, DB,
+};
+
+const EXPORT_DATA: &str = "export.data";
+const LOGIN_CATEGORY: &str = "001";
+const ARCHIVED_STATE: &str = "archived";
+const UPDATED_SUFFIX: &str = "updated";
+
+#[derive(Debug, Snafu)]
+enum LibraryError {
+    IoError { source: io::Error },
+    ZipError { source: zip::result::ZipError },
+    JsonError { source: serde_json::Error },
+    UtilsError { source: utils::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("Could not open \'{}\': {}", file, source))]
+    OpenFailed {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("\'{}\' is not a valid 1Password export", file))]
+    ExportWrongFormat {
+        file: String,
+    },
+    #[snafu(display("Could not write \'{}\': {}", file, source))]
+    WriteFailed {
+        file: String,
+        source: LibraryError,
+    },
+    LibError {
+        source: LibraryError,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+pub fn run(config: &Configuration) {
+    for source in &config.sources {
+        let mut export_data = match read_export_data(source) {
+            Ok(export_data) => export_data,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+        let db = match parse_items(source, &export_data) {
+            Ok(db) => db,
+            Err(err) => {
+                eprintln!("Error: {}", err);
+                continue;
+            }
+        };
+
+        let (tx, rx) = channel();
+        run_update_threads(&db, &source.blocklist, config, tx);
+
+        commit_results(rx, false, |updated_db| {
+            write_export(source, &mut export_data, updated_db)
+        });
+    }
+}
+
+fn read_export_data(source: &Source) -> Result<Value> {
+    let err = OpenFailed {
+        file: source.file.to_owned(),
+    };
+    let file = File::open(&source.file)
+        .context(IoError)
+        .context(err.clone())?;
+    let mut archive = ZipArchive::new(file)
+        .context(ZipError)
+        .context(err.clone())?;
+    let mut export_data = archive
+        .by_name(EXPORT_DATA)
+        .context(ZipError)
+        .context(err.clone())?;
+
+    let mut content = Vec::new();
+    export_data
+        .read_to_end(&mut content)
+        .context(IoError)
+        .context(err.clone())?;
+    serde_json::from_slice(&content)
+        .context(JsonError)
+        .context(err)
+}
+
+fn array<'a>(value: &'a Value, key: &str) -> &'a [Value] {
+    value
+        .get(key)
+        .map(|v| v.as_array())
+        .flatten()
+        .map(|a| a.as_slice())
+        .unwrap_or(&[])
+}
+
+/// All items of all vaults of all accounts in the export.
+fn items(export_data: &Value) -> Vec<&Value> {
+    let mut items = Vec::new();
+    for account in array(export_data, "accounts") {
+        for vault in array(account, "vaults") {
+            items.extend(array(vault, "items"));
+        }
+    }
+    items
+}
+
+fn items_mut(export_data: &mut Value) -> Vec<&mut Value> {
+    let mut items = Vec::new();
+    let accounts = match export_data
+        .get_mut("accounts")
+        .map(|a| a.as_array_mut())
+        .flatten()
+    {
+        Some(accounts) => accounts,
+        None => return items,
+    };
+    for account in accounts.iter_mut() {
+        let vaults = match account
+            .get_mut("vaults")
+            .map(|v| v.as_array_mut())
+            .flatten()
+        {
+            Some(vaults) => vaults,
+            None => continue,
+        };
+        for vault in vaults.iter_mut() {
+            if let Some(vault_items) = vault.get_mut("items").map(|i| i.as_array_mut()).flatten() {
+                items.extend(vault_items.iter_mut());
+            }
+        }
+    }
+    items
+}
+
+fn login_field<'a>(item: &'a Value, designation: &str) -> Option<&'a str> {
+    item.get("details")
+        .map(|d| d.get("loginFields"))
+        .flatten()
+        .map(|f| f.as_array())
+        .flatten()?
+        .iter()
+        .find(|field| field.get("designation").map(|d| d.as_str()).flatten() == Some(designation))
+        .map(|field| field.get("value").map(|v| v.as_str()).flatten())
+        .flatten()
+}
+
+/// The main URL of the item followed by all its other URLs.
+fn item_urls(item: &Value) -> Vec<String> {
+    let overview = match item.get("overview") {
+        Some(overview) => overview,
+        None => return Vec::new(),
+    };
+    let mut urls = Vec::new();
+    if let Some(url) = overview.get("url").map(|u| u.as_str()).flatten() {
+        urls.push(url.to_owned());
+    }
+    if let Some(overview_urls) = overview.get("urls").map(|u| u.as_array()).flatten() {
+        for url in overview_urls {
+            if let Some(url) = url.get("url").map(|u| u.as_str()).flatten() {
+                if !urls.iter().any(|u| u == url) {
+                    urls.push(url.to_owned());
+                }
+            }
+        }
+    }
+    urls.retain(|url| !url.is_empty());
+    urls
+}
+
+fn parse_items(source: &Source, export_data: &Value) -> Result<DB> {
+    if export_data.get("accounts").is_none() {
+        return Err(Error::ExportWrongFormat {
+            file: source.file.to_owned(),
+        });
+    }
+
+    let mut db_vec = Vec::new();
+    for item in items(export_data) {
+        let field = |name: &str| item.get(name).map(|v| v.as_str()).flatten();
+        if field("categoryUuid") != Some(LOGIN_CATEGORY) || field("state") == Some(ARCHIVED_STATE) {
+            continue;
+        }
+        let uuid = match field("uuid") {
+            Some(uuid) => uuid.to_owned(),
+            None => continue,
+        };
+        let (username, password) =
+            match (login_field(item, "username"), login_field(item, "password")) {
+                (Some(username), Some(password))
+                    if !username.is_empty() && !password.is_empty() =>
+                {
+                    (username, password)
+                }
+                _ => continue,
+            };
+        let mut urls = item_urls(item).into_iter();
+        let url = match urls.next() {
+            Some(url) => url,
+            None => continue,
+        };
+
+        let mut db_entry = DBEntry::new(
+            url,
+            username.to_owned(),
+            password.to_owned(),
+            get_pw().context(UtilsError).context(LibError)?,
+        );
+        db_entry.alt_urls = urls.collect();
+        db_entry.uuid = Uuid::OnePassword(uuid);
+        db_vec.push(db_entry);
+    }
+
+    Ok(DB::new(db_vec))
+}
+
+/// Set the new passwords and move the old ones into the password history.
+/// Returns the uuids of the updated items.
+fn update_items(export_data: &mut Value, db: &DB) -> Vec<Uuid> {
+    let mut written = Vec::new();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    for item in items_mut(export_data) {
+        let uuid = match item.get("uuid").map(|v| v.as_str()).flatten() {
+            Some(uuid) => Uuid::OnePassword(uuid.to_owned()),
+            None => continue,
+        };
+        let db_entry = match db.entries.iter().find(|e| e.uuid == uuid) {
+            Some(db_entry) => db_entry,
+            None => continue,
+        };
+
+        let details = &mut item["details"];
+        if let Some(fields) = details
+            .get_mut("loginFields")
+            .map(|f| f.as_array_mut())
+            .flatten()
+        {
+            for field in fields.iter_mut() {
+                if field.get("designation").map(|d| d.as_str()).flatten() == Some("password") {
+                    field["value"] = Value::String(db_entry.new_password.to_owned());
+                }
+            }
+        }
+        let history = json!({
+            "value": db_entry.old_password,
+            "time": now,
+        });
+        match details
+            .get_mut("passwordHistory")
+            .map(|h| h.as_array_mut())
+            .flatten()
+        {
+            Some(password_history) => password_history.insert(0, history),
+            None => details["passwordHistory"] = Value::Array(vec![history]),
+        }
+        item["updatedAt"] = Value::from(now);
+        written.push(uuid);
+    }
+    written
+}
+
+/// Path next to the export, e.g. `1PasswordExport.updated.1pux`.
+fn updated_path(file: &Path) -> PathBuf {
+    let stem = file
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = file
+        .extension()
+        .map(|e| e.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "1pux".to_owned());
+    file.with_file_name(format!("{}.{}.{}", stem, UPDATED_SUFFIX, extension))
+}
+
+/// Write a copy of the export with the updated `export.data`, all other
+/// files of the archive, like attachments, are copied unchanged.
+fn write_export(source: &Source, export_data: &mut Value, db: &DB) -> Result<Vec<Uuid>> {
+    if db.entries.is_empty() {
+        return Ok(Vec::new());
+    }
+    let written = update_items(export_data, db);
+
+    let output_file = updated_path(Path::new(&source.file));
+    let err = WriteFailed {
+        file: output_file.to_string_lossy().into_owned(),
+    };
+    let content = serde_json::to_vec(export_data)
+        .context(JsonError)
+        .context(err.clone())?;
+
+    let mut archive = File::open(&source.file)
+        .context(IoError)
+        .context(err.clone())
+        .and_then(|file| ZipArchive::new(file).context(ZipError).context(err.clone()))?;
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+    for i in 0..archive.len() {
+        let zip_file = archive
+            .by_index_raw(i)
+            .context(ZipError)
+            .context(err.clone())?;
+        if zip_file.name() == EXPORT_DATA {
+            continue;
+        }
+        writer
+            .raw_copy_file(zip_file)
+            .context(ZipError)
+            .context(err.clone())?;
+    }
+    writer
+        .start_file(
+            EXPORT_DATA,
+            FileOptions::default().compression_method(CompressionMethod::Deflated),
+        )
+        .context(ZipError)
+        .context(err.clone())?;
+    writer
+        .write_all(&content)
+        .context(IoError)
+        .context(err.clone())?;
+    let updated_archive = writer
+        .finish()
+        .context(ZipError)
+        .context(err.clone())?
+        .into_inner();
+    write_private_file(&output_file, &updated_archive)
+        .context(IoError)
+        .context(err)?;
+
+    println!("Wrote {}", output_file.to_string_lossy());
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn login(uuid: &str, state: &str, urls: &[&str]) -> Value {
+        json!({
+            "uuid": uuid,
+            "categoryUuid": LOGIN_CATEGORY,
+            "state": state,
+            "overview": {
+                "url": urls.first().copied().unwrap_or(""),
+                "urls": urls.iter().map(|url| json!({ "url": url })).collect::<Vec<_>>(),
+            },
+            "details": {
+                "loginFields": [
+                    { "designation": "username", "value": "alice" },
+                    { "designation": "password", "value": "0ld" },
+                ],
+            },
+        })
+    }
+
+    fn export(items: Vec<Value>) -> Value {
+        json!({ "accounts": [{ "vaults": [{ "items": items }] }] })
+    }
+
+    fn source() -> Source {
+        Source::new(
+            "onepassword-1pux".to_owned(),
+            "1PasswordExport.1pux".to_owned(),
+            Vec::new(),
+            Vec::new(),
+            HashMap::new(),
+            HashMap::new(),
+        )
+    }
+
+    #[test]
+    fn only_active_logins_are_parsed() {
+        let mut note = login("note", "active", &["https://example.com"]);
+        note["categoryUuid"] = json!("003");
+        let export_data = export(vec![
+            login(
+                "github",
+                "active",
+                &[
+                    "https://github.com",
+                    "https://gist.github.com",
+                    "https://github.com",
+                ],
+            ),
+            login("archived", ARCHIVED_STATE, &["https://gitlab.com"]),
+            login("no-url", "active", &[]),
+            note,
+        ]);
+        let db = parse_items(&source(), &export_data).unwrap();
+        assert_eq!(db.entries.len(), 1);
+        let db_entry = &db.entries[0];
+        assert_eq!(db_entry.uuid, Uuid::OnePassword("github".to_owned()));
+        assert_eq!(db_entry.url, "https://github.com");
+        assert_eq!(db_entry.alt_urls, vec!["https://gist.github.com"]);
+        assert_eq!(db_entry.username, "alice");
+        assert_eq!(db_entry.old_password, "0ld");
+
+        assert!(matches!(
+            parse_items(&source(), &json!({ "items": [] })),
+            Err(Error::ExportWrongFormat { .. })
+        ));
+    }
+
+    #[test]
+    fn updated_items_keep_the_old_password_in_their_history() {
+        let mut github = login("github", "active", &["https://github.com"]);
+        github["details"]["passwordHistory"] = json!([{ "value": "older", "time": 1 }]);
+        let mut export_data = export(vec![
+            github,
+            login("gitlab", "active", &["https://gitlab.com"]),
+        ]);
+        let mut db_entry = DBEntry::new(
+            "https://github.com".to_owned(),
+            "alice".to_owned(),
+            "0ld".to_owned(),
+            "n3w".to_owned(),
+        );
+        db_entry.uuid = Uuid::OnePassword("github".to_owned());
+        let written = update_items(&mut export_data, &DB::new(vec![db_entry]));
+        assert_eq!(written, vec![Uuid::OnePassword("github".to_owned())]);
+
+        let items = items(&export_data);
+        assert_eq!(login_field(items[0], "password"), Some("n3w"));
+        assert_eq!(login_field(items[0], "username"), Some("alice"));
+        let history = items[0]["details"]["passwordHistory"].as_array().unwrap();
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[0]["value"], "0ld");
+        assert_eq!(history[1]["value"], "older");
+        assert_eq!(login_field(items[1], "password"), Some("0ld"));
+        assert!(items[1]["details"].get("passwordHistory").is_none());
+    }
+}
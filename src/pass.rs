@@ -1,17 +1,34 @@
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::sync::mpsc::channel;
-use std::{fs, io, result, str};
+use std::{fmt, fs, io, result};
 
+use regex::Regex;
 use snafu::{ResultExt, Snafu};
+use url::Url;
 
-use crate::config::Configuration;
-use crate::utils::{self, run_update_threads};
+use crate::config::{Configuration, Source};
+use crate::utils::{self, run_update_threads, tmp_path, Uuid};
+
+const DEFAULT_STORE: &str = ".password-store";
+const PASS_EXTENSION: &str = "gpg";
+const DEFAULT_LAYOUT: &str = "{url}/{username}";
+const USERNAME_KEYS: [&str; 3] = ["login", "user", "username"];
+const URL_KEYS: [&str; 1] = ["url"];
+const PASS_BIN: &str = "pass";
+/// Environment variable pass reads the store dir from.
+const STORE_DIR_ENV: &str = "PASSWORD_STORE_DIR";
+const GPG_BIN: &str = "gpg";
+const GIT_BIN: &str = "git";
+const GPG_ID_FILE: &str = ".gpg-id";
 
 #[derive(Debug, Snafu)]
 pub enum LibraryError {
     IoError { source: io::Error },
     UtilsError { source: utils::Error },
+    RegexError { source: regex::Error },
 }
 
 #[derive(Debug, Snafu)]
@@ -29,19 +46,68 @@ pub enum Error {
     PassGenError {
         source: LibraryError,
     },
-    #[snafu(display("Update failed for entry: {}, {}, {}", db_entry.url, db_entry.username, db_entry.new_password))]
-    PassUpdateError {
-        db_entry: utils::DBEntry,
+    #[snafu(display(
+        "Layout \'{}\' has to contain a {{url}} or {{username}} placeholder",
+        layout
+    ))]
+    LayoutInvalid {
+        layout: String,
+    },
+    #[snafu(display("Layout \'{}\' could not be compiled: {}", layout, source))]
+    LayoutRegexError {
+        layout: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Entry has wrong uuid type"))]
+    WrongUuidType,
+    #[snafu(display("Could not decrypt pass entry \'{}\'", name))]
+    PassShowError {
+        name: String,
+    },
+    #[snafu(display(
+        "Pass entry \'{}\' was modified during the update, keeping it untouched",
+        name
+    ))]
+    PassEntryChanged {
+        name: String,
+    },
+    #[snafu(display("No {} file found for entry \'{}\'", file, name))]
+    RecipientsMissing {
+        file: &'static str,
+        name: String,
     },
-    CmdError {
+    #[snafu(display("Could not read recipients from \'{}\': {}", file, source))]
+    RecipientsError {
+        file: String,
         source: LibraryError,
     },
+    #[snafu(display("Could not encrypt pass entry \'{}\': {}", name, source))]
+    EncryptError {
+        name: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not commit the updated entries to git: {}", source))]
+    GitError {
+        source: LibraryError,
+    },
+    #[snafu(display("Git exited with an error: {}", stderr))]
+    GitCmdFailed {
+        stderr: String,
+    },
 }
 
 type Result<T, E = Error> = result::Result<T, E>;
 
 pub fn run(config: &Configuration) {
-    let db = match parse_pass() {
+    let source = config.sources.first();
+    let store = match get_store_dir(source, DEFAULT_STORE) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    let db = match parse_store(&store, source, PASS_EXTENSION, show_entry) {
         Ok(result) => result,
         Err(err) => {
             eprintln!("{}", err);
@@ -49,6 +115,25 @@ pub fn run(config: &Configuration) {
         }
     };
 
+    let updated_entries = update_store(config, &store, &db, update_pass_entry);
+    if let Err(err) = commit_store(&store, PASS_EXTENSION, &updated_entries) {
+        eprintln!("Warning: {}", err);
+    }
+}
+
+/// Run the update scripts for all entries of a pass-like store and write the
+/// new passwords of successful runs back with `update_entry`, which returns
+/// the name of the updated entry. Returns the names of all updated entries.
+pub fn update_store<F, E>(
+    config: &Configuration,
+    store: &Path,
+    db: &utils::DB,
+    update_entry: F,
+) -> Vec<String>
+where
+    F: Fn(&Path, &utils::DBEntry) -> result::Result<String, E>,
+    E: fmt::Display,
+{
     let blocklist;
     if config.sources.is_empty() {
         blocklist = Vec::new();
@@ -57,10 +142,11 @@ pub fn run(config: &Configuration) {
     }
 
     let (tx, rx) = channel();
-    let nr_jobs = run_update_threads(&db, &blocklist, config, tx);
+    run_update_threads(db, &blocklist, config, tx);
 
-    let thread_results = rx.iter().take(nr_jobs);
-    for thread_result in thread_results {
+    // Every entry is written as soon as its result arrives
+    let mut updated_entries = Vec::new();
+    for thread_result in rx {
         let output = match thread_result.result {
             Ok(output) => output,
             Err(err) => {
@@ -70,150 +156,601 @@ pub fn run(config: &Configuration) {
         };
 
         let db_entry = thread_result.db_entry;
-        if output.status.success() {
-            match update_pass_entry(&db_entry) {
-                Ok(()) => (),
+        if output.confirmed() {
+            match update_entry(store, &db_entry) {
+                Ok(name) => updated_entries.push(name),
                 Err(err) => {
                     eprintln!("Warning: {}", err);
                     continue;
                 }
             };
         } else {
-            let db_entry = db_entry.clone();
-            let err = utils::Error::NightwatchExecError {
-                db_entry: db_entry,
-                output,
-            };
+            let err = utils::script_error(db_entry, output);
             eprintln!("{}", err);
             continue;
         }
     }
+
+    updated_entries
 }
 
-fn parse_pass() -> Result<utils::DB> {
+pub fn get_store_dir(source: Option<&Source>, default_dir: &str) -> Result<PathBuf> {
+    if let Some(source) = source {
+        if !source.file.is_empty() {
+            return Ok(PathBuf::from(&source.file));
+        }
+    }
+
     let mut path = PathBuf::new();
     let home_dir = match dirs::home_dir() {
         Some(dir) => dir,
         None => return Err(Error::HomeDirError),
     };
     path.push(home_dir);
-    path.push(".password-store");
-    let path_s = match path.to_str() {
-        Some(path) => path.to_owned(),
-        None => return Err(Error::PathToStrError),
-    };
+    path.push(default_dir);
+    Ok(path)
+}
 
-    let mut db = Vec::new();
+/// Translate a layout template like "Web/{url}/{username}" into a regex.
+/// `*` matches a single folder, `**` any number of folders.
+fn compile_layout(layout: &str) -> Result<Regex> {
+    if !layout.contains("{url}") && !layout.contains("{username}") {
+        return Err(Error::LayoutInvalid {
+            layout: layout.to_owned(),
+        });
+    }
 
-    let outer_dir = fs::read_dir(&path)
-        .context(IoError)
-        .context(PassStoreNotFound { path: path_s })?;
-    for subdir_r in outer_dir {
-        let subdir_os = match subdir_r {
-            Ok(subdir_os) => subdir_os.file_name(),
+    // `**/` also matches no folder at all, so `**/{url}` finds entries at the
+    // root of the store too
+    let expr = regex::escape(layout)
+        .replace(r"\*\*/", "(?:.+/)?")
+        .replace(r"\*\*", ".+")
+        .replace(r"\*", "[^/]+")
+        .replace(r"\{url\}", "(?P<url>[^/]+)")
+        .replace(r"\{username\}", "(?P<username>[^/]+)");
+    Regex::new(&format!("^{}$", expr))
+        .context(RegexError)
+        .context(LayoutRegexError { layout })
+}
+
+fn compile_layouts(source: Option<&Source>) -> Result<Vec<Regex>> {
+    let mut layouts = Vec::new();
+    match source {
+        Some(source) if !source.layouts.is_empty() => {
+            for layout in &source.layouts {
+                layouts.push(compile_layout(layout)?);
+            }
+        }
+        _ => layouts.push(compile_layout(DEFAULT_LAYOUT)?),
+    };
+    Ok(layouts)
+}
+
+fn find_entries(dir: &Path, extension: &str, entries: &mut Vec<PathBuf>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(dir)? {
+        let path = match dir_entry {
+            Ok(dir_entry) => dir_entry.path(),
             Err(err) => {
                 eprintln!("Warning: {}", err);
                 continue;
             }
         };
-        let url = match subdir_os.to_str() {
-            Some(dir) => dir.to_owned(),
-            None => continue,
+        let hidden = match path.file_name().map(|name| name.to_str()).flatten() {
+            Some(name) => name.starts_with('.'),
+            None => true,
         };
-        if url.starts_with('.') {
+        if hidden {
             continue;
         }
-        if url.is_empty() {
-            break;
+
+        if path.is_dir() {
+            if let Err(err) = find_entries(&path, extension, entries) {
+                eprintln!("Warning: {}", err);
+            }
+        } else if path.extension() == Some(OsStr::new(extension)) {
+            entries.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn match_layouts(name: &str, layouts: &[Regex]) -> (Option<String>, Option<String>) {
+    for layout in layouts {
+        if let Some(captures) = layout.captures(name) {
+            let url = captures.name("url").map(|m| m.as_str().to_owned());
+            let username = captures.name("username").map(|m| m.as_str().to_owned());
+            return (url, username);
+        }
+    }
+    (None, None)
+}
+
+/// The first line of a pass entry is the password, every following line is
+/// metadata like `login: alice`, `url: github.com` or an `otpauth://` URI.
+pub fn split_content(content: &str) -> (&str, &str) {
+    match content.split_once('\n') {
+        Some((password, metadata)) => (password, metadata),
+        None => (content, ""),
+    }
+}
+
+fn metadata_value(metadata: &str, keys: &[&str]) -> Option<String> {
+    for line in metadata.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        if keys.contains(&key.trim().to_ascii_lowercase().as_str()) && !value.trim().is_empty() {
+            return Some(value.trim().to_owned());
         }
-        path.push(&url);
-        let inner_dir = match fs::read_dir(&path) {
-            Ok(dir) => dir,
+    }
+    None
+}
+
+pub fn replace_password(content: &str, new_password: &str) -> String {
+    let (_, metadata) = split_content(content);
+    format!("{}\n{}", new_password, metadata)
+}
+
+/// `pass show` for an entry of the store, which need not be the default one.
+fn show_command(store: &Path, name: &str) -> Command {
+    let mut command = Command::new(PASS_BIN);
+    command.env(STORE_DIR_ENV, store).args(&["show", name]);
+    command
+}
+
+fn show_entry(store: &Path, name: &str) -> Result<String> {
+    let err = || Error::PassShowError {
+        name: name.to_owned(),
+    };
+    let child = show_command(store, name).output().map_err(|_| err())?;
+    if !child.status.success() {
+        return Err(err());
+    }
+    String::from_utf8(child.stdout).map_err(|_| err())
+}
+
+fn maps_to_site(url: &str) -> bool {
+    let url_protocol = if url.contains("://") {
+        url.to_owned()
+    } else {
+        format!("https://{}", url)
+    };
+    match Url::parse(&url_protocol) {
+        Ok(url) => url.domain().map(|d| d.contains('.')).unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+/// Collect all entries of a pass-like store, `decrypt` returns the content of
+/// an entry given the store and the entry name.
+pub fn parse_store<F, E>(
+    path: &Path,
+    source: Option<&Source>,
+    extension: &str,
+    decrypt: F,
+) -> Result<utils::DB>
+where
+    F: Fn(&Path, &str) -> result::Result<String, E>,
+    E: fmt::Display,
+{
+    let path_s = match path.to_str() {
+        Some(path) => path.to_owned(),
+        None => return Err(Error::PathToStrError),
+    };
+    let layouts = compile_layouts(source)?;
+
+    let mut entry_paths = Vec::new();
+    find_entries(path, extension, &mut entry_paths)
+        .context(IoError)
+        .context(PassStoreNotFound { path: path_s })?;
+    entry_paths.sort();
+
+    let mut db = Vec::new();
+    for entry_path in entry_paths {
+        let name = match entry_path
+            .strip_prefix(path)
+            .ok()
+            .map(|p| p.with_extension(""))
+            .map(|p| p.to_str().map(|p| p.to_owned()))
+            .flatten()
+        {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let (layout_url, layout_username) = match_layouts(&name, &layouts);
+        if layout_url.is_none() && layout_username.is_none() {
+            eprintln!(
+                "Warning: Skipping pass entry \'{}\', it does not match any layout",
+                name
+            );
+            continue;
+        }
+
+        let content = match decrypt(path, &name) {
+            Ok(content) => content,
             Err(err) => {
                 eprintln!("Warning: {}", err);
                 continue;
             }
         };
-        for dir_entry in inner_dir {
-            let name = match dir_entry {
-                Ok(name) => name.file_name(),
-                Err(err) => {
-                    eprintln!("Warning: {}", err);
-                    continue;
-                }
-            };
-            let username = match name.to_str() {
-                Some(username) => username.to_string().replace(".gpg", ""),
-                None => continue,
-            };
-            if username.eq("") {
+        let (password, metadata) = split_content(&content);
+
+        let url = match metadata_value(metadata, &URL_KEYS).or(layout_url) {
+            Some(url) if maps_to_site(&url) => url,
+            _ => {
+                eprintln!(
+                    "Warning: Skipping pass entry \'{}\', it does not map to a website",
+                    name
+                );
                 continue;
             }
-
-            let arg = format!("{}/{}", &url, &username);
-            let child = match Command::new("pass").args(&["show", &arg]).output() {
-                Ok(child) => child,
-                Err(err) => {
-                    eprintln!("Warning: {}", err);
-                    continue;
-                }
-            };
-            if !child.status.success() {
+        };
+        let username = match metadata_value(metadata, &USERNAME_KEYS).or(layout_username) {
+            Some(username) => username,
+            None => {
+                eprintln!(
+                    "Warning: Skipping pass entry \'{}\', no username found",
+                    name
+                );
                 continue;
             }
+        };
+        let password = password.trim_end_matches('\r').to_owned();
+        if password.is_empty() {
+            continue;
+        }
 
-            let password = match str::from_utf8(&child.stdout) {
-                Ok(pass) => pass.replace("\n", ""),
-                Err(err) => {
-                    eprintln!("Warning: {}", err);
-                    continue;
-                }
-            };
+        let new_password = utils::get_pw().context(UtilsError).context(PassGenError)?;
+        let mut entry = utils::DBEntry::new(url, username, password, new_password);
+        entry.uuid = Uuid::Pass(name);
+        db.push(entry);
+    }
+    Ok(utils::DB::new(db))
+}
 
-            let new_password = utils::get_pw().context(UtilsError).context(PassGenError)?;
-            let entry = utils::DBEntry::new(url.clone(), username, password, new_password);
-            db.push(entry);
+/// Read the recipients from the `recipients_file` closest to the entry, the
+/// same lookup pass itself does on `pass insert`.
+pub fn find_recipients(
+    store: &Path,
+    name: &str,
+    extension: &str,
+    recipients_file: &'static str,
+) -> Result<Vec<String>> {
+    let entry_file = store.join(format!("{}.{}", name, extension));
+    let mut dir = entry_file.parent();
+    while let Some(current_dir) = dir {
+        let recipients_path = current_dir.join(recipients_file);
+        if recipients_path.is_file() {
+            let content = fs::read_to_string(&recipients_path)
+                .context(IoError)
+                .context(RecipientsError {
+                    file: recipients_path.to_string_lossy().into_owned(),
+                })?;
+            let recipients = content
+                .lines()
+                .map(|line| line.split('#').next().unwrap_or("").trim().to_owned())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<String>>();
+            if recipients.is_empty() {
+                break;
+            }
+            return Ok(recipients);
         }
+        if current_dir == store {
+            break;
+        }
+        dir = current_dir.parent();
     }
-    Ok(utils::DB::new(db))
+    Err(Error::RecipientsMissing {
+        file: recipients_file,
+        name: name.to_owned(),
+    })
 }
 
-fn update_pass_entry(db_entry: &utils::DBEntry) -> Result<()> {
-    let db_entry = db_entry.clone();
-    let pass_entry = format!("{}/{}", db_entry.url, db_entry.username);
-    let output = utils::cmd("pass", &["rm", &pass_entry], "0")
-        .context(UtilsError)
-        .context(CmdError)?;
-    if !output.status.success() {
-        return Err(Error::PassUpdateError { db_entry });
+/// `gpg --decrypt` of an entry file, the same call `pass show` makes.
+fn decrypt_command(file: &Path) -> Command {
+    let mut command = Command::new(GPG_BIN);
+    command
+        .args(&[
+            "--decrypt",
+            "--batch",
+            "--quiet",
+            "--yes",
+            "--compress-algo=none",
+        ])
+        .arg(file);
+    command
+}
+
+fn decrypt_entry(file: &Path, name: &str) -> Result<String> {
+    let err = || Error::PassShowError {
+        name: name.to_owned(),
+    };
+    let child = decrypt_command(file)
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|_| err())?;
+    if !child.status.success() {
+        return Err(err());
+    }
+    String::from_utf8(child.stdout).map_err(|_| err())
+}
+
+/// Encrypt into a temporary file next to the entry and rename it over the
+/// original, so a failing gpg call never leaves the entry missing.
+fn encrypt_entry(file: &Path, recipients: &[String], content: &str) -> io::Result<()> {
+    let tmp_file = tmp_path(file);
+    let mut gpg_cmd = Command::new(GPG_BIN);
+    gpg_cmd
+        .args(&[
+            "--encrypt",
+            "--batch",
+            "--yes",
+            "--quiet",
+            "--compress-algo=none",
+            "--no-encrypt-to",
+        ])
+        .arg("--output")
+        .arg(&tmp_file);
+    for recipient in recipients {
+        gpg_cmd.arg("--recipient").arg(recipient);
     }
 
-    let pass = match Command::new("pass")
-        .args(&["insert", &pass_entry])
-        .stdin(Stdio::piped())
-        .spawn()
-    {
-        Ok(pass) => pass,
-        Err(_) => return Err(Error::PassUpdateError { db_entry }),
+    let mut gpg = gpg_cmd.stdin(Stdio::piped()).spawn()?;
+    let written = match gpg.stdin.take() {
+        Some(mut stdin) => stdin.write_all(content.as_bytes()),
+        None => Err(io::Error::new(
+            io::ErrorKind::BrokenPipe,
+            "gpg stdin missing",
+        )),
     };
+    let status = gpg.wait()?;
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_file);
+        return Err(err);
+    }
+    if !status.success() {
+        let _ = fs::remove_file(&tmp_file);
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("{} exited with {}", GPG_BIN, status),
+        ));
+    }
 
-    let pass_input = format!("{}\n{}", db_entry.new_password, db_entry.new_password);
-    let echo = match Command::new("echo")
-        .arg(pass_input)
-        .stdout(match pass.stdin {
-            Some(stdin) => stdin,
-            None => return Err(Error::PassUpdateError { db_entry }),
-        })
-        .output()
-    {
-        Ok(echo) => echo,
-        Err(_) => return Err(Error::PassUpdateError { db_entry }),
+    fs::rename(&tmp_file, file)
+}
+
+fn update_pass_entry(store: &Path, db_entry: &utils::DBEntry) -> Result<String> {
+    let pass_entry = match &db_entry.uuid {
+        Uuid::Pass(name) => name.to_owned(),
+        _ => return Err(Error::WrongUuidType),
     };
 
-    if !echo.status.success() {
-        return Err(Error::PassUpdateError { db_entry });
+    // Read the file which is replaced, so no other entry body is written back
+    let entry_file = store.join(format!("{}.{}", pass_entry, PASS_EXTENSION));
+    let content = decrypt_entry(&entry_file, &pass_entry)?;
+    let (password, _) = split_content(&content);
+    if password.trim_end_matches('\r') != db_entry.old_password {
+        return Err(Error::PassEntryChanged { name: pass_entry });
     }
-    println!("Updated passwords!");
+    let new_content = replace_password(&content, &db_entry.new_password);
 
+    let recipients = find_recipients(store, &pass_entry, PASS_EXTENSION, GPG_ID_FILE)?;
+    encrypt_entry(&entry_file, &recipients, &new_content)
+        .context(IoError)
+        .context(EncryptError {
+            name: pass_entry.to_owned(),
+        })?;
+    println!(
+        "Updated password on website {}, with username {}",
+        db_entry.url, db_entry.username
+    );
+
+    Ok(pass_entry)
+}
+
+fn git(store: &Path, args: &[&str]) -> Result<()> {
+    let output = Command::new(GIT_BIN)
+        .arg("-C")
+        .arg(store)
+        .args(args)
+        .output()
+        .context(IoError)
+        .context(GitError)?;
+    if !output.status.success() {
+        return Err(Error::GitCmdFailed {
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+        });
+    }
+    Ok(())
+}
+
+/// Create a single commit for all entries updated in this run, so the
+/// previous passwords stay recoverable from the history of the store.
+pub fn commit_store(store: &Path, extension: &str, updated_entries: &[String]) -> Result<()> {
+    if updated_entries.is_empty() || !store.join(".git").exists() {
+        return Ok(());
+    }
+
+    let files = updated_entries
+        .iter()
+        .map(|name| format!("{}.{}", name, extension))
+        .collect::<Vec<String>>();
+    let subject = format!(
+        "Rotate passwords of {} entries using PassUp",
+        updated_entries.len()
+    );
+    let mut body = String::from("Updated entries:\n");
+    for name in updated_entries {
+        body.push_str(&format!("- {}\n", name));
+    }
+
+    let mut add_args = vec!["add", "--"];
+    add_args.extend(files.iter().map(|f| f.as_str()));
+    git(store, &add_args)?;
+
+    let mut commit_args = vec!["commit", "--quiet", "-m", &subject, "-m", &body, "--"];
+    commit_args.extend(files.iter().map(|f| f.as_str()));
+    git(store, &commit_args)?;
+
+    println!("Committed updated entries to the git repository of the store");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn source(layouts: &[&str]) -> Source {
+        Source::new(
+            "pass".to_owned(),
+            String::new(),
+            Vec::new(),
+            layouts.iter().map(|l| l.to_string()).collect(),
+            HashMap::new(),
+            HashMap::new(),
+        )
+    }
+
+    fn matched(name: &str, layouts: &[&str]) -> (Option<String>, Option<String>) {
+        let layouts = compile_layouts(Some(&source(layouts))).unwrap();
+        match_layouts(name, &layouts)
+    }
+
+    fn some(url: &str, username: &str) -> (Option<String>, Option<String>) {
+        (Some(url.to_owned()), Some(username.to_owned()))
+    }
+
+    #[test]
+    fn default_layout_is_url_and_username() {
+        let layouts = compile_layouts(None).unwrap();
+        assert_eq!(
+            match_layouts("github.com/alice", &layouts),
+            some("github.com", "alice")
+        );
+        assert_eq!(
+            match_layouts("Web/github.com/alice", &layouts),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn layout_wildcards_match_folders() {
+        assert_eq!(
+            matched("Web/github.com/alice", &["*/{url}/{username}"]),
+            some("github.com", "alice")
+        );
+        assert_eq!(
+            matched("a/b/github.com/alice", &["*/{url}/{username}"]),
+            (None, None)
+        );
+        assert_eq!(
+            matched("a/b/github.com/alice", &["**/{url}/{username}"]),
+            some("github.com", "alice")
+        );
+        assert_eq!(
+            matched("github.com", &["**/{url}"]),
+            (Some("github.com".to_owned()), None)
+        );
+        assert_eq!(
+            matched("Web/github.com/alice", &["Web/**/{url}/{username}"]),
+            some("github.com", "alice")
+        );
+        assert_eq!(
+            matched("Sites/github.com", &["Sites/{url}"]),
+            (Some("github.com".to_owned()), None)
+        );
+        assert_eq!(
+            matched("Sites.x/github.com", &["Sites/{url}"]),
+            (None, None)
+        );
+    }
+
+    #[test]
+    fn first_matching_layout_wins() {
+        let layouts = ["Work/{username}/{url}", "*/{url}/{username}"];
+        assert_eq!(
+            matched("Work/bob/gitlab.com", &layouts),
+            some("gitlab.com", "bob")
+        );
+        assert_eq!(
+            matched("Home/gitlab.com/bob", &layouts),
+            some("gitlab.com", "bob")
+        );
+    }
+
+    #[test]
+    fn layout_needs_a_placeholder() {
+        assert!(matches!(
+            compile_layout("Web/*"),
+            Err(Error::LayoutInvalid { .. })
+        ));
+    }
+
+    #[test]
+    fn metadata_overrides_the_layout() {
+        let store = tempfile::tempdir().unwrap();
+        for name in &["Web/example.org/alice", "Web/notes/todo", "Other/x"] {
+            let file = store.path().join(format!("{}.{}", name, PASS_EXTENSION));
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, "").unwrap();
+        }
+        let db = parse_store(
+            store.path(),
+            Some(&source(&["Web/{url}/{username}"])),
+            PASS_EXTENSION,
+            |_, name| -> result::Result<String, String> {
+                Ok(match name {
+                    "Web/example.org/alice" => "hunter2\nlogin: alice@example.org\n".to_owned(),
+                    _ => "secret\n".to_owned(),
+                })
+            },
+        )
+        .unwrap();
+        assert_eq!(db.entries.len(), 1);
+        assert_eq!(db.entries[0].url, "example.org");
+        assert_eq!(db.entries[0].username, "alice@example.org");
+        assert_eq!(db.entries[0].old_password, "hunter2");
+        assert_eq!(
+            db.entries[0].uuid,
+            Uuid::Pass("Web/example.org/alice".to_owned())
+        );
+    }
+
+    #[test]
+    fn entries_are_shown_from_the_configured_store() {
+        let store = Path::new("/tmp/custom-store");
+        let command = show_command(store, "Web/github.com/alice");
+        assert_eq!(command.get_program(), PASS_BIN);
+        assert_eq!(
+            command.get_args().collect::<Vec<_>>(),
+            vec!["show", "Web/github.com/alice"]
+        );
+        assert_eq!(
+            command.get_envs().collect::<Vec<_>>(),
+            vec![(OsStr::new(STORE_DIR_ENV), Some(store.as_os_str()))]
+        );
+    }
+
+    #[test]
+    fn updated_entry_is_read_from_its_file() {
+        let file = Path::new("/tmp/custom-store/Web/github.com/alice.gpg");
+        let command = decrypt_command(file);
+        assert_eq!(command.get_program(), GPG_BIN);
+        assert!(command.get_args().any(|arg| arg == "--decrypt"));
+        assert_eq!(command.get_args().last(), Some(file.as_os_str()));
+    }
+
+    #[test]
+    fn password_is_replaced_and_metadata_kept() {
+        let content = "old\nlogin: alice\notpauth://totp/x\n";
+        assert_eq!(
+            replace_password(content, "new"),
+            "new\nlogin: alice\notpauth://totp/x\n"
+        );
+    }
+}
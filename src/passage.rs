@@ -0,0 +1,324 @@
+use std::io::{Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::{env, fs, io, result};
+
+use age::{Decryptor, Encryptor, IdentityFile};
+use snafu::{ResultExt, Snafu};
+
+use crate::config::Configuration;
+use crate::pass::{self, find_recipients, replace_password, split_content};
+use crate::utils::{self, write_private_file, Uuid};
+
+const DEFAULT_STORE: &str = ".passage/store";
+const DEFAULT_IDENTITIES: &str = ".passage/identities";
+const IDENTITIES_ENV: &str = "PASSAGE_IDENTITIES_FILE";
+const PASSAGE_EXTENSION: &str = "age";
+const AGE_RECIPIENTS_FILE: &str = ".age-recipients";
+
+#[derive(Debug, Snafu)]
+pub enum LibraryError {
+    IoError { source: io::Error },
+    PassError { source: pass::Error },
+    DecryptError { source: age::DecryptError },
+    EncryptError { source: age::EncryptError },
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not get path to the users home directory"))]
+    HomeDirError,
+    #[snafu(display("Could not read age identities from \'{}\': {}", file, source))]
+    IdentitiesError {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not decrypt passage entry \'{}\': {}", name, source))]
+    DecryptFailed {
+        name: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not encrypt passage entry \'{}\': {}", name, source))]
+    EncryptFailed {
+        name: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Recipient \'{}\' is not a valid age or ssh public key", recipient))]
+    RecipientInvalid {
+        recipient: String,
+    },
+    #[snafu(display("{}", source))]
+    StoreError {
+        source: LibraryError,
+    },
+    #[snafu(display("Entry has wrong uuid type"))]
+    WrongUuidType,
+    #[snafu(display(
+        "Passage entry \'{}\' was modified during the update, keeping it untouched",
+        name
+    ))]
+    EntryChanged {
+        name: String,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+pub fn run(config: &Configuration) {
+    let source = config.sources.first();
+    let identities = match read_identities() {
+        Ok(identities) => identities,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+    let store = match pass::get_store_dir(source, DEFAULT_STORE) {
+        Ok(store) => store,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+    let db = match pass::parse_store(&store, source, PASSAGE_EXTENSION, |store, name| {
+        decrypt_entry(store, name, &identities)
+    }) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}", err);
+            return;
+        }
+    };
+
+    let updated_entries = pass::update_store(config, &store, &db, |store, db_entry| {
+        update_passage_entry(store, db_entry, &identities)
+    });
+    if let Err(err) = pass::commit_store(&store, PASSAGE_EXTENSION, &updated_entries) {
+        eprintln!("Warning: {}", err);
+    }
+}
+
+fn read_identities() -> Result<Vec<Box<dyn age::Identity>>> {
+    let path = match env::var(IDENTITIES_ENV) {
+        Ok(path) => path,
+        Err(_) => dirs::home_dir()
+            .ok_or(Error::HomeDirError)?
+            .join(DEFAULT_IDENTITIES)
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    IdentityFile::from_file(path.to_owned())
+        .context(IoError)
+        .context(IdentitiesError {
+            file: path.to_owned(),
+        })?
+        .into_identities()
+        .context(DecryptError)
+        .context(IdentitiesError { file: path })
+}
+
+fn decrypt_entry(
+    store: &Path,
+    name: &str,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<String> {
+    let err = DecryptFailed {
+        name: name.to_owned(),
+    };
+    let file = fs::File::open(store.join(format!("{}.{}", name, PASSAGE_EXTENSION)))
+        .context(IoError)
+        .context(err.clone())?;
+    let decryptor = Decryptor::new(io::BufReader::new(file))
+        .context(DecryptError)
+        .context(err.clone())?;
+    let mut reader = decryptor
+        .decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))
+        .context(DecryptError)
+        .context(err.clone())?;
+
+    let mut content = String::new();
+    reader
+        .read_to_string(&mut content)
+        .context(IoError)
+        .context(err)?;
+    Ok(content)
+}
+
+fn parse_recipient(recipient: &str) -> Result<Box<dyn age::Recipient + Send>> {
+    if let Ok(recipient) = age::x25519::Recipient::from_str(recipient) {
+        return Ok(Box::new(recipient));
+    }
+    if let Ok(recipient) = age::ssh::Recipient::from_str(recipient) {
+        return Ok(Box::new(recipient));
+    }
+    Err(Error::RecipientInvalid {
+        recipient: recipient.to_owned(),
+    })
+}
+
+/// Encrypt the entry and replace its file through a private temporary file
+/// next to it, like the pass backend does.
+fn encrypt_entry(file: &Path, recipients: &[String], content: &str, name: &str) -> Result<()> {
+    let err = EncryptFailed {
+        name: name.to_owned(),
+    };
+    let mut age_recipients = Vec::new();
+    for recipient in recipients {
+        age_recipients.push(parse_recipient(recipient)?);
+    }
+    let encryptor = Encryptor::with_recipients(
+        age_recipients
+            .iter()
+            .map(|r| r.as_ref() as &dyn age::Recipient),
+    )
+    .context(EncryptError)
+    .context(err.clone())?;
+
+    let write = || -> io::Result<()> {
+        let mut encrypted = Vec::new();
+        let mut writer = encryptor.wrap_output(&mut encrypted)?;
+        writer.write_all(content.as_bytes())?;
+        writer.finish()?;
+        write_private_file(file, &encrypted)
+    };
+    write().map_err(|source| Error::EncryptFailed {
+        name: name.to_owned(),
+        source: LibraryError::IoError { source },
+    })
+}
+
+fn update_passage_entry(
+    store: &Path,
+    db_entry: &utils::DBEntry,
+    identities: &[Box<dyn age::Identity>],
+) -> Result<String> {
+    let name = match &db_entry.uuid {
+        Uuid::Pass(name) => name.to_owned(),
+        _ => return Err(Error::WrongUuidType),
+    };
+
+    let content = decrypt_entry(store, &name, identities)?;
+    let (password, _) = split_content(&content);
+    if password.trim_end_matches('\r') != db_entry.old_password {
+        return Err(Error::EntryChanged { name });
+    }
+    let new_content = replace_password(&content, &db_entry.new_password);
+
+    let recipients = find_recipients(store, &name, PASSAGE_EXTENSION, AGE_RECIPIENTS_FILE)
+        .context(PassError)
+        .context(StoreError)?;
+    let entry_file = store.join(format!("{}.{}", name, PASSAGE_EXTENSION));
+    encrypt_entry(&entry_file, &recipients, &new_content, &name)?;
+    println!(
+        "Updated password on website {}, with username {}",
+        db_entry.url, db_entry.username
+    );
+
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::PermissionsExt;
+
+    use age::secrecy::ExposeSecret;
+
+    use super::*;
+
+    fn recipients(store: &Path, name: &str) -> Vec<String> {
+        find_recipients(store, name, PASSAGE_EXTENSION, AGE_RECIPIENTS_FILE).unwrap()
+    }
+
+    #[test]
+    fn closest_recipients_are_used() {
+        let store = tempfile::tempdir().unwrap();
+        let work = store.path().join("work");
+        fs::create_dir_all(work.join("github.com")).unwrap();
+        fs::create_dir_all(store.path().join("github.com")).unwrap();
+        fs::write(store.path().join(AGE_RECIPIENTS_FILE), "age1root\n").unwrap();
+        fs::write(
+            work.join(AGE_RECIPIENTS_FILE),
+            "# team\nage1alice\n\nage1bob\n",
+        )
+        .unwrap();
+        assert_eq!(
+            recipients(store.path(), "github.com/alice"),
+            vec!["age1root"]
+        );
+        assert_eq!(
+            recipients(store.path(), "work/github.com/alice"),
+            vec!["age1alice", "age1bob"]
+        );
+    }
+
+    #[test]
+    fn entries_are_reencrypted_to_their_recipients() {
+        let store = tempfile::tempdir().unwrap();
+        let identity = age::x25519::Identity::generate();
+        let identities_file = store.path().join("identities");
+        fs::write(
+            &identities_file,
+            format!("# test\n{}\n", identity.to_string().expose_secret()),
+        )
+        .unwrap();
+        env::set_var(IDENTITIES_ENV, &identities_file);
+        let identities = read_identities().unwrap();
+        assert_eq!(identities.len(), 1);
+
+        fs::create_dir(store.path().join("github.com")).unwrap();
+        fs::write(
+            store.path().join(AGE_RECIPIENTS_FILE),
+            identity.to_public().to_string(),
+        )
+        .unwrap();
+        let entry_file = store.path().join("github.com/alice.age");
+        let recipients = vec![identity.to_public().to_string()];
+        encrypt_entry(
+            &entry_file,
+            &recipients,
+            "0ld\nlogin: alice\n",
+            "github.com/alice",
+        )
+        .unwrap();
+        assert_eq!(
+            decrypt_entry(store.path(), "github.com/alice", &identities).unwrap(),
+            "0ld\nlogin: alice\n"
+        );
+
+        let mut db_entry = utils::DBEntry::new(
+            "github.com".to_owned(),
+            "alice".to_owned(),
+            "0ld".to_owned(),
+            "n3w".to_owned(),
+        );
+        db_entry.uuid = Uuid::Pass("github.com/alice".to_owned());
+        assert_eq!(
+            update_passage_entry(store.path(), &db_entry, &identities).unwrap(),
+            "github.com/alice"
+        );
+        assert_eq!(
+            decrypt_entry(store.path(), "github.com/alice", &identities).unwrap(),
+            "n3w\nlogin: alice\n"
+        );
+        let mode = fs::metadata(&entry_file).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, utils::PRIVATE_FILE_MODE);
+
+        // The entry now holds the new password, a second update must not
+        // overwrite it
+        assert!(matches!(
+            update_passage_entry(store.path(), &db_entry, &identities),
+            Err(Error::EntryChanged { .. })
+        ));
+    }
+
+    #[test]
+    fn recipients_are_age_or_ssh_keys() {
+        let identity = age::x25519::Identity::generate();
+        assert!(parse_recipient(&identity.to_public().to_string()).is_ok());
+        assert!(matches!(
+            parse_recipient("age1notakey"),
+            Err(Error::RecipientInvalid { .. })
+        ));
+    }
+}
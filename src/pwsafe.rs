@@ -6,7 +6,7 @@ use rpassword::read_password;
 use snafu::{ResultExt, Snafu};
 
 use crate::config::{Configuration, Source};
-use crate::utils::{self, get_pw, run_update_threads, DBEntry, Uuid, DB};
+use crate::utils::{self, commit_results, get_pw, run_update_threads, DBEntry, Uuid, DB};
 
 type Result<T, E = Error> = result::Result<T, E>;
 
@@ -64,7 +64,7 @@ pub enum Error {
 
 pub fn run(config: &Configuration) {
     for source in &config.sources {
-        let (db, db_password, version, records) = match unlock_and_parse_db(source) {
+        let (db, db_password, version, mut records) = match unlock_and_parse_db(source) {
             Ok(values) => values,
             Err(err) => {
                 eprintln!("Error: {}", err);
@@ -73,45 +73,13 @@ pub fn run(config: &Configuration) {
         };
 
         let (tx, rx) = channel();
-        let nr_jobs = run_update_threads(&db, &source.blocklist, config, tx);
+        run_update_threads(&db, &source.blocklist, config, tx);
 
-        let mut updated_entries = Vec::new();
-        let thread_results = rx.iter().take(nr_jobs);
-        for thread_result in thread_results {
-            let output = match thread_result.result {
-                Ok(output) => output,
-                Err(err) => {
-                    eprintln!("Error while executing Nightwatch: {}", err);
-                    continue;
-                }
-            };
-
-            let mut db_entry = thread_result.db_entry;
-            if !output.status.success() {
-                let db_entry_ = db_entry.clone();
-                let err = utils::Error::NightwatchExecError {
-                    db_entry: db_entry_,
-                    output,
-                };
-                eprintln!("{}", err);
-                db_entry.new_password = db_entry.old_password.to_owned();
-            } else {
-                println!(
-                    "Updated password on website {}, with username {}",
-                    db_entry.url, db_entry.username
-                );
-            }
-            updated_entries.push(db_entry);
-        }
-
-        let updated_db = DB::new(updated_entries);
-        match write_db(source, &updated_db, db_password, records, version) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Error: {}", err);
-                println!("{:?}", updated_db);
-            }
-        };
+        // The whole database is encrypted again on every write
+        commit_results(rx, true, |updated_db| {
+            let written = update_records(&mut records, updated_db);
+            write_db(source, &db_password, &records, version).map(|_| written)
+        });
     }
 }
 
@@ -224,11 +192,39 @@ pub fn unlock_and_parse_db(source: &Source) -> Result<(DB, String, u16, Vec<(u8,
     Ok((DB::new(entry_vec), db_password, version, record_vec))
 }
 
+/// Set the new passwords in the records of the updated entries, the records
+/// of all other entries are kept as they are. Returns the uuids of the
+/// updated entries.
+fn update_records(records: &mut [(u8, Vec<u8>)], db: &DB) -> Vec<Uuid> {
+    let mut written = Vec::new();
+    let mut db_entry = None;
+    for (record_type, record_data) in records.iter_mut() {
+        let record = match PwsafeRecordField::new(*record_type, record_data.clone()) {
+            Ok(r) => r,
+            Err(_) => continue,
+        };
+        match &record {
+            PwsafeRecordField::Uuid(uuid) => {
+                let uuid = Uuid::Pwsafe(uuid.to_owned());
+                db_entry = db.entries.iter().find(|entry| entry.uuid == uuid);
+            }
+            PwsafeRecordField::Password(_) => {
+                if let Some(db_entry) = db_entry {
+                    *record_data = db_entry.new_password.as_bytes().to_vec();
+                    written.push(db_entry.uuid.to_owned());
+                }
+            }
+            PwsafeRecordField::EndOfRecord => db_entry = None,
+            _ => (),
+        };
+    }
+    written
+}
+
 pub fn write_db(
     source: &Source,
-    db: &DB,
-    db_password: String,
-    records: Vec<(u8, Vec<u8>)>,
+    db_password: &str,
+    records: &[(u8, Vec<u8>)],
     version: u16,
 ) -> Result<()> {
     let err = DbUpdateFailed {
@@ -257,31 +253,8 @@ pub fn write_db(
         .context(IoError)
         .context(err.clone())?; // End of header
 
-    let mut db_entry = DBEntry::empty();
-
-    for (record_type, mut record_data) in records {
-        let record = match PwsafeRecordField::new(record_type, record_data.clone()) {
-            Ok(r) => r,
-            Err(e) => {
-                eprintln!("Warning: {}", e);
-                continue;
-            }
-        };
-        match &record {
-            PwsafeRecordField::Uuid(uuid) => {
-                for entry in db.entries.iter() {
-                    if Uuid::Pwsafe(uuid.to_owned()) == entry.uuid {
-                        db_entry = entry.to_owned();
-                    }
-                }
-            }
-            PwsafeRecordField::Password(_) => {
-                record_data = db_entry.new_password.as_bytes().to_vec()
-            }
-            PwsafeRecordField::EndOfRecord => db_entry = DBEntry::empty(),
-            _ => (),
-        };
-        psdb.write_field(record_type, &record_data)
+    for (record_type, record_data) in records {
+        psdb.write_field(*record_type, record_data)
             .context(IoError)
             .context(err.clone())?;
     }
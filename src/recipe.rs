@@ -0,0 +1,539 @@
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, result, thread};
+
+use snafu::{ResultExt, Snafu};
+use toml::Value;
+
+use crate::report::{self, parse_constraints, parse_reason, parse_stage};
+use crate::report::{PasswordConstraints, Reason, Report, Stage};
+use crate::utils::{DBEntry, RunOutput};
+use crate::webdriver::{self, Session, ELEMENT_TIMEOUT};
+
+const CSS_SELECTOR: &str = "css selector";
+/// Locator strategies of the WebDriver standard a step can `use`.
+const SELECTOR_STRATEGIES: [&str; 5] = [
+    CSS_SELECTOR,
+    "link text",
+    "partial link text",
+    "tag name",
+    "xpath",
+];
+
+const RECIPE_RUNNERS: [&str; 2] = ["webdriver", "http"];
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not open recipe \'{}\': {}", path, source))]
+    RecipeOpen {
+        path: String,
+        source: io::Error,
+    },
+    #[snafu(display("Recipe \'{}\' is not a valid toml file: {}", path, source))]
+    RecipeWrongFormat {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[snafu(display(
+        "Recipe \'{}\' has an unknown runner, choose one of the following: {:?}",
+        path,
+        RECIPE_RUNNERS
+    ))]
+    RecipeRunnerWrong {
+        path: String,
+    },
+    #[snafu(display("Recipe \'{}\' does not contain [[steps]]", path))]
+    StepsMissing {
+        path: String,
+    },
+    #[snafu(display("Step {} of recipe \'{}\' has no known action", step, path))]
+    StepActionUnknown {
+        path: String,
+        step: usize,
+    },
+    #[snafu(display(
+        "Step {} of recipe \'{}\' uses an unknown selector strategy, choose one of the following: {:?}",
+        step,
+        path,
+        SELECTOR_STRATEGIES
+    ))]
+    StepSelectorWrong {
+        path: String,
+        step: usize,
+    },
+    #[snafu(display("Step {} of recipe \'{}\' has a wrong stage: {}", step, path, source))]
+    StepStageWrong {
+        path: String,
+        step: usize,
+        source: report::Error,
+    },
+    #[snafu(display(
+        "Step {} of recipe \'{}\' is missing the field \'{}\'",
+        step,
+        path,
+        field
+    ))]
+    StepFieldMissing {
+        path: String,
+        step: usize,
+        field: &'static str,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// Runner a recipe is written for, set with `runner` at the top of the file.
+#[derive(Debug, PartialEq)]
+pub enum RecipeRunner {
+    WebDriver,
+    Http,
+}
+
+#[derive(Debug)]
+pub struct Selector {
+    pub using: String,
+    pub value: String,
+}
+
+#[derive(Debug)]
+pub enum Step {
+    Goto {
+        url: String,
+    },
+    Fill {
+        selector: Selector,
+        value: String,
+    },
+    Click {
+        selector: Selector,
+    },
+    WaitFor {
+        selector: Selector,
+        timeout: Duration,
+    },
+    AssertText {
+        selector: Selector,
+        text: String,
+    },
+    Pause {
+        duration: Duration,
+    },
+}
+
+/// What a step reports: the stage reached once it succeeded and the reason
+/// if it failed.
+#[derive(Debug, Default)]
+pub struct StepReport {
+    pub stage: Option<Stage>,
+    pub reason: Option<Reason>,
+}
+
+/// A site's password change flow as a linear list of steps, e.g.
+/// `scripts/github.com.toml`. Selectors are CSS selectors unless the step
+/// sets another strategy with `using`.
+#[derive(Debug)]
+pub struct Recipe {
+    pub steps: Vec<Step>,
+    pub reports: Vec<StepReport>,
+    pub constraints: Option<PasswordConstraints>,
+}
+
+impl Recipe {
+    pub fn run(&self, session: &Session, db_entry: &DBEntry, timeout: Duration) -> RunOutput {
+        run_steps(
+            &self.steps,
+            &self.reports,
+            &self.constraints,
+            timeout,
+            |step| run_step(session, step, db_entry),
+        )
+    }
+}
+
+/// Execute the steps in order and stop at the first failing one or once
+/// `timeout` has passed. The report holds the last stage reached, the reason
+/// of the failed step and the constraints of the recipe.
+pub fn run_steps<S, E, F>(
+    steps: &[S],
+    reports: &[StepReport],
+    constraints: &Option<PasswordConstraints>,
+    timeout: Duration,
+    mut run_step: F,
+) -> RunOutput
+where
+    E: fmt::Display,
+    F: FnMut(&S) -> (String, result::Result<(), E>),
+{
+    let mut report = Report {
+        constraints: constraints.to_owned(),
+        ..Report::default()
+    };
+    let mut log = String::new();
+    let start = Instant::now();
+    for (i, (step, step_report)) in steps.iter().zip(reports.iter()).enumerate() {
+        if start.elapsed() >= timeout {
+            log.push_str(&format!(
+                "Step {}: timed out after {}s\n",
+                i + 1,
+                timeout.as_secs()
+            ));
+            let mut output = RunOutput::new(false, log, report);
+            output.interrupted = true;
+            return output;
+        }
+        let (description, result) = run_step(step);
+        match result {
+            Ok(_) => {
+                log.push_str(&format!("Step {}: {}\n", i + 1, description));
+                if step_report.stage.is_some() {
+                    report.stage = step_report.stage.max(report.stage);
+                }
+            }
+            Err(err) => {
+                log.push_str(&format!(
+                    "Step {}: {} failed: {}\n",
+                    i + 1,
+                    description,
+                    err
+                ));
+                report.reason = step_report.reason.to_owned();
+                return RunOutput::new(false, log, report);
+            }
+        }
+    }
+    RunOutput::new(true, log, report)
+}
+
+fn run_step(
+    session: &Session,
+    step: &Step,
+    db_entry: &DBEntry,
+) -> (String, Result<(), webdriver::Error>) {
+    let find =
+        |selector: &Selector, timeout| session.find(&selector.using, &selector.value, timeout);
+    match step {
+        Step::Goto { url } => (format!("goto {}", url), session.goto(url)),
+        Step::Fill { selector, value } => (
+            format!("fill {}", selector.value),
+            find(selector, ELEMENT_TIMEOUT)
+                .and_then(|element| session.fill(&element, &substitute(value, db_entry))),
+        ),
+        Step::Click { selector } => (
+            format!("click {}", selector.value),
+            find(selector, ELEMENT_TIMEOUT).and_then(|element| session.click(&element)),
+        ),
+        Step::WaitFor { selector, timeout } => (
+            format!("wait_for {}", selector.value),
+            find(selector, *timeout).map(|_| ()),
+        ),
+        Step::AssertText { selector, text } => (
+            format!("assert_text {} contains \'{}\'", selector.value, text),
+            find(selector, ELEMENT_TIMEOUT)
+                .and_then(|element| session.text(&element))
+                .and_then(|element_text| {
+                    if element_text.contains(text.as_str()) {
+                        Ok(())
+                    } else {
+                        Err(webdriver::Error::TextMismatch {
+                            selector: selector.value.to_owned(),
+                            text: element_text,
+                        })
+                    }
+                }),
+        ),
+        Step::Pause { duration } => {
+            thread::sleep(*duration);
+            (format!("pause {}ms", duration.as_millis()), Ok(()))
+        }
+    }
+}
+
+/// Replace the `{username}`, `{old_password}` and `{new_password}`
+/// placeholders with the values of the entry. `{password}` is the old
+/// password, which login scripts get the password to try as.
+pub fn substitute(value: &str, db_entry: &DBEntry) -> String {
+    value
+        .replace("{username}", &db_entry.username)
+        .replace("{password}", &db_entry.old_password)
+        .replace("{old_password}", &db_entry.old_password)
+        .replace("{new_password}", &db_entry.new_password)
+}
+
+pub fn read_recipe(path: &str) -> Result<Value> {
+    let recipe_str = fs::read_to_string(path).context(RecipeOpen { path })?;
+    toml::from_str(&recipe_str).context(RecipeWrongFormat { path })
+}
+
+pub fn recipe_runner(recipe: &Value, path: &str) -> Result<RecipeRunner> {
+    match recipe.get("runner").map(|r| r.as_str()) {
+        None | Some(Some("webdriver")) => Ok(RecipeRunner::WebDriver),
+        Some(Some("http")) => Ok(RecipeRunner::Http),
+        _ => Err(Error::RecipeRunnerWrong {
+            path: path.to_owned(),
+        }),
+    }
+}
+
+pub fn recipe_steps<'a>(recipe: &'a Value, path: &str) -> Result<&'a [Value]> {
+    recipe
+        .get("steps")
+        .map(|s| s.as_array())
+        .flatten()
+        .map(|s| s.as_slice())
+        .ok_or(Error::StepsMissing {
+            path: path.to_owned(),
+        })
+}
+
+pub fn step_field(step: &Value, path: &str, index: usize, name: &'static str) -> Result<String> {
+    step.get(name)
+        .map(|v| v.as_str())
+        .flatten()
+        .map(|v| v.to_owned())
+        .ok_or(Error::StepFieldMissing {
+            path: path.to_owned(),
+            step: index,
+            field: name,
+        })
+}
+
+/// Parse `stage` and `reason` of a step.
+pub fn parse_step_report(step: &Value, path: &str, index: usize) -> Result<StepReport> {
+    let stage = match step.get("stage").map(|s| s.as_str()).flatten() {
+        Some(stage) => Some(parse_stage(stage).context(StepStageWrong { path, step: index })?),
+        None => None,
+    };
+    let reason = step
+        .get("reason")
+        .map(|r| r.as_str())
+        .flatten()
+        .map(parse_reason);
+    Ok(StepReport { stage, reason })
+}
+
+pub fn parse_recipe(path: &str) -> Result<Recipe> {
+    let recipe = read_recipe(path)?;
+
+    let mut steps = Vec::new();
+    let mut reports = Vec::new();
+    for (i, step) in recipe_steps(&recipe, path)?.iter().enumerate() {
+        steps.push(parse_step(step, path, i + 1)?);
+        reports.push(parse_step_report(step, path, i + 1)?);
+    }
+
+    Ok(Recipe {
+        steps,
+        reports,
+        constraints: parse_constraints(&recipe),
+    })
+}
+
+fn parse_step(step: &Value, path: &str, index: usize) -> Result<Step> {
+    let field = |name: &'static str| step_field(step, path, index, name);
+    let selector = |name: &'static str| -> Result<Selector> {
+        let using = match step.get("using") {
+            Some(using) => using
+                .as_str()
+                .filter(|using| SELECTOR_STRATEGIES.contains(using))
+                .ok_or(Error::StepSelectorWrong {
+                    path: path.to_owned(),
+                    step: index,
+                })?,
+            None => CSS_SELECTOR,
+        };
+        Ok(Selector {
+            using: using.to_owned(),
+            value: field(name)?,
+        })
+    };
+    let millis = |name: &str| {
+        step.get(name)
+            .map(|v| v.as_integer())
+            .flatten()
+            .map(|ms| Duration::from_millis(ms.abs() as u64))
+    };
+
+    if step.get("goto").is_some() {
+        Ok(Step::Goto {
+            url: field("goto")?,
+        })
+    } else if step.get("fill").is_some() {
+        Ok(Step::Fill {
+            selector: selector("fill")?,
+            value: field("value")?,
+        })
+    } else if step.get("click").is_some() {
+        Ok(Step::Click {
+            selector: selector("click")?,
+        })
+    } else if step.get("wait_for").is_some() {
+        Ok(Step::WaitFor {
+            selector: selector("wait_for")?,
+            timeout: millis("timeout").unwrap_or(ELEMENT_TIMEOUT),
+        })
+    } else if step.get("assert_text").is_some() {
+        Ok(Step::AssertText {
+            selector: selector("assert_text")?,
+            text: field("text")?,
+        })
+    } else if step.get("pause").is_some() {
+        Ok(Step::Pause {
+            duration: millis("pause").ok_or(Error::StepFieldMissing {
+                path: path.to_owned(),
+                step: index,
+                field: "pause",
+            })?,
+        })
+    } else {
+        Err(Error::StepActionUnknown {
+            path: path.to_owned(),
+            step: index,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PATH: &str = "scripts/example.com.toml";
+
+    fn step(toml: &str) -> Result<Step> {
+        parse_step(&toml::from_str(toml).unwrap(), PATH, 1)
+    }
+
+    fn report(stage: Option<Stage>, reason: Option<Reason>) -> StepReport {
+        StepReport { stage, reason }
+    }
+
+    #[test]
+    fn recipes_are_parsed_with_their_reports() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("example.com.toml");
+        fs::write(
+            &path,
+            r##"
+[[steps]]
+goto = "https://example.com/login"
+
+[[steps]]
+fill = "//input[@name='password']"
+using = "xpath"
+value = "{old_password}"
+stage = "logged_in"
+reason = "invalid_credentials"
+
+[[steps]]
+pause = 500
+"##,
+        )
+        .unwrap();
+        let recipe = parse_recipe(path.to_str().unwrap()).unwrap();
+        assert_eq!(recipe.steps.len(), 3);
+        match &recipe.steps[1] {
+            Step::Fill { selector, value } => {
+                assert_eq!(selector.using, "xpath");
+                assert_eq!(value, "{old_password}");
+            }
+            step => panic!("unexpected step {:?}", step),
+        }
+        assert_eq!(recipe.reports[1].stage, Some(Stage::LoggedIn));
+        assert_eq!(recipe.reports[1].reason, Some(Reason::InvalidCredentials));
+        assert!(matches!(
+            recipe.steps[2],
+            Step::Pause { duration } if duration == Duration::from_millis(500)
+        ));
+    }
+
+    #[test]
+    fn steps_need_a_known_action() {
+        assert!(matches!(
+            step("hover = '#menu'"),
+            Err(Error::StepActionUnknown { step: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn selectors_need_a_known_strategy() {
+        assert!(matches!(
+            step("click = '#submit'"),
+            Ok(Step::Click { selector }) if selector.using == CSS_SELECTOR
+        ));
+        assert!(matches!(
+            step("click = '#submit'\nusing = 'id'"),
+            Err(Error::StepSelectorWrong { .. })
+        ));
+        assert!(matches!(
+            step("click = '#submit'\nusing = 1"),
+            Err(Error::StepSelectorWrong { .. })
+        ));
+    }
+
+    #[test]
+    fn steps_need_their_fields() {
+        assert!(matches!(
+            step("fill = '#password'"),
+            Err(Error::StepFieldMissing { field: "value", .. })
+        ));
+        assert!(matches!(
+            step("assert_text = '#flash'"),
+            Err(Error::StepFieldMissing { field: "text", .. })
+        ));
+        assert!(matches!(
+            step("pause = 'long'"),
+            Err(Error::StepFieldMissing { field: "pause", .. })
+        ));
+    }
+
+    #[test]
+    fn stage_is_the_highest_reached() {
+        let reports = [
+            report(Some(Stage::Submitted), None),
+            report(Some(Stage::LoggedIn), None),
+            report(None, None),
+        ];
+        let output = run_steps(&[1, 2, 3], &reports, &None, Duration::from_secs(5), |i| {
+            (format!("step {}", i), Ok::<_, String>(()))
+        });
+        assert!(output.success);
+        assert!(!output.interrupted);
+        assert_eq!(output.report.stage, Some(Stage::Submitted));
+        assert_eq!(
+            output.log,
+            "Step 1: step 1\nStep 2: step 2\nStep 3: step 3\n"
+        );
+    }
+
+    #[test]
+    fn failing_step_reports_its_reason() {
+        let reports = [
+            report(Some(Stage::LoggedIn), Some(Reason::InvalidCredentials)),
+            report(None, Some(Reason::PasswordRejected)),
+            report(Some(Stage::Confirmed), None),
+        ];
+        let mut ran = Vec::new();
+        let output = run_steps(&[1, 2, 3], &reports, &None, Duration::from_secs(5), |&i| {
+            ran.push(i);
+            let result = if i == 2 { Err("too short") } else { Ok(()) };
+            (format!("step {}", i), result)
+        });
+        assert_eq!(ran, vec![1, 2]);
+        assert!(!output.success);
+        assert!(!output.interrupted);
+        assert_eq!(output.report.stage, Some(Stage::LoggedIn));
+        assert_eq!(output.report.reason, Some(Reason::PasswordRejected));
+        assert!(output.log.ends_with("Step 2: step 2 failed: too short\n"));
+    }
+
+    #[test]
+    fn timeout_interrupts_the_recipe() {
+        let reports = [report(Some(Stage::LoggedIn), None), report(None, None)];
+        let output = run_steps(&[1, 2], &reports, &None, Duration::from_millis(50), |i| {
+            thread::sleep(Duration::from_millis(100));
+            (format!("step {}", i), Ok::<_, String>(()))
+        });
+        assert!(!output.success);
+        assert!(output.interrupted);
+        assert_eq!(output.report.stage, Some(Stage::LoggedIn));
+        assert!(output.log.ends_with("Step 2: timed out after 0s\n"));
+    }
+}
@@ -0,0 +1,321 @@
+use std::{fmt, fs, result};
+
+use serde_json::Value as JsonValue;
+use snafu::Snafu;
+use toml::Value;
+
+/// Stages a script reports, in the order they are reached.
+const STAGES: [&str; 3] = ["logged_in", "submitted", "confirmed"];
+const REASONS: [&str; 5] = [
+    "invalid_credentials",
+    "account_locked",
+    "two_factor",
+    "password_rejected",
+    "captcha",
+];
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display(
+        "Unknown stage \'{}\', choose one of the following: {:?}",
+        stage,
+        STAGES
+    ))]
+    StageWrong {
+        stage: String,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    LoggedIn,
+    Submitted,
+    Confirmed,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Stage::LoggedIn => write!(f, "logged_in"),
+            Stage::Submitted => write!(f, "submitted"),
+            Stage::Confirmed => write!(f, "confirmed"),
+        }
+    }
+}
+
+pub fn parse_stage(stage: &str) -> Result<Stage> {
+    match stage {
+        "logged_in" => Ok(Stage::LoggedIn),
+        "submitted" => Ok(Stage::Submitted),
+        "confirmed" => Ok(Stage::Confirmed),
+        _ => Err(Error::StageWrong {
+            stage: stage.to_owned(),
+        }),
+    }
+}
+
+/// Why a script failed, codes other than the known ones are kept as they are.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reason {
+    InvalidCredentials,
+    AccountLocked,
+    TwoFactor,
+    PasswordRejected,
+    Captcha,
+    Other(String),
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reason::InvalidCredentials => write!(f, "{}", REASONS[0]),
+            Reason::AccountLocked => write!(f, "{}", REASONS[1]),
+            Reason::TwoFactor => write!(f, "{}", REASONS[2]),
+            Reason::PasswordRejected => write!(f, "{}", REASONS[3]),
+            Reason::Captcha => write!(f, "{}", REASONS[4]),
+            Reason::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+pub fn parse_reason(reason: &str) -> Reason {
+    match reason {
+        "invalid_credentials" => Reason::InvalidCredentials,
+        "account_locked" => Reason::AccountLocked,
+        "two_factor" => Reason::TwoFactor,
+        "password_rejected" => Reason::PasswordRejected,
+        "captcha" => Reason::Captcha,
+        _ => Reason::Other(reason.to_owned()),
+    }
+}
+
+/// Password rules of a site, reported together with `password_rejected`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PasswordConstraints {
+    pub min_length: Option<usize>,
+    pub max_length: Option<usize>,
+    pub symbols: Option<bool>,
+    pub allowed_symbols: Option<String>,
+}
+
+impl fmt::Display for PasswordConstraints {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut rules = Vec::new();
+        if let Some(min_length) = self.min_length {
+            rules.push(format!("min_length={}", min_length));
+        }
+        if let Some(max_length) = self.max_length {
+            rules.push(format!("max_length={}", max_length));
+        }
+        if let Some(symbols) = self.symbols {
+            rules.push(format!("symbols={}", symbols));
+        }
+        if let Some(allowed_symbols) = &self.allowed_symbols {
+            rules.push(format!("allowed_symbols={}", allowed_symbols));
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+/// What a script reported about its run, all fields are optional.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Report {
+    pub stage: Option<Stage>,
+    pub reason: Option<Reason>,
+    pub constraints: Option<PasswordConstraints>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.stage {
+            Some(stage) => write!(f, "stage: {}", stage)?,
+            None => write!(f, "stage: none")?,
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ", reason: {}", reason)?;
+        }
+        if let Some(constraints) = &self.constraints {
+            write!(f, ", constraints: {}", constraints)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the run of a script ended for the password of the entry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// The site confirmed the new password.
+    Confirmed,
+    /// The new password was submitted, but no confirmation was seen. The site
+    /// may use either password.
+    Unconfirmed,
+    /// The site still uses the old password.
+    Failed,
+}
+
+/// State of stored credentials found by a login-only script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CredentialState {
+    Valid,
+    Invalid,
+    Locked,
+    TwoFactor,
+    Unknown,
+}
+
+impl fmt::Display for CredentialState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            CredentialState::Valid => write!(f, "Valid"),
+            CredentialState::Invalid => write!(f, "Invalid"),
+            CredentialState::Locked => write!(f, "Locked"),
+            CredentialState::TwoFactor => write!(f, "2FA required"),
+            CredentialState::Unknown => write!(f, "Unknown"),
+        }
+    }
+}
+
+/// Result of logging in after a change, first with the new password and if
+/// that fails with the old one.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Verification {
+    NewPassword,
+    OldPassword,
+    Neither,
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Verification::NewPassword => write!(f, "login with the new password succeeded"),
+            Verification::OldPassword => write!(f, "the site still uses the old password"),
+            Verification::Neither => write!(f, "login failed with both passwords"),
+        }
+    }
+}
+
+impl Report {
+    /// Scripts which do not report a stage are trusted by their exit status.
+    /// An interrupted script may have submitted the new password, unless it
+    /// reported an earlier stage.
+    pub fn outcome(&self, success: bool, interrupted: bool) -> Outcome {
+        match self.stage {
+            Some(stage) if interrupted && stage < Stage::Submitted => Outcome::Failed,
+            _ if interrupted => Outcome::Unconfirmed,
+            Some(Stage::Confirmed) => Outcome::Confirmed,
+            Some(Stage::Submitted) => Outcome::Unconfirmed,
+            None if success => Outcome::Confirmed,
+            _ => Outcome::Failed,
+        }
+    }
+}
+
+/// Read the JSON report a Nightwatch script wrote to `PASSUP_RESULT`, e.g.
+/// `{"stage": "submitted", "reason": "password_rejected", "constraints": {"max_length": 20}}`.
+/// A missing or broken report is an empty one.
+pub fn read_report(path: &str) -> Report {
+    let json = fs::read(path)
+        .ok()
+        .map(|content| serde_json::from_slice::<JsonValue>(&content).ok())
+        .flatten();
+    let json = match json {
+        Some(json) => json,
+        None => return Report::default(),
+    };
+    let str_field = |name: &str| json.get(name).map(|v| v.as_str()).flatten();
+
+    Report {
+        stage: str_field("stage").map(|s| parse_stage(s).ok()).flatten(),
+        reason: str_field("reason").map(parse_reason),
+        constraints: json.get("constraints").map(|c| PasswordConstraints {
+            min_length: c
+                .get("min_length")
+                .map(|v| v.as_u64())
+                .flatten()
+                .map(|v| v as usize),
+            max_length: c
+                .get("max_length")
+                .map(|v| v.as_u64())
+                .flatten()
+                .map(|v| v as usize),
+            symbols: c.get("symbols").map(|v| v.as_bool()).flatten(),
+            allowed_symbols: c
+                .get("allowed_symbols")
+                .map(|v| v.as_str())
+                .flatten()
+                .map(|v| v.to_owned()),
+        }),
+    }
+}
+
+/// Parse the `[constraints]` table of a recipe.
+pub fn parse_constraints(recipe: &Value) -> Option<PasswordConstraints> {
+    let c = recipe.get("constraints")?;
+    Some(PasswordConstraints {
+        min_length: c
+            .get("min_length")
+            .map(|v| v.as_integer())
+            .flatten()
+            .map(|v| v.abs() as usize),
+        max_length: c
+            .get("max_length")
+            .map(|v| v.as_integer())
+            .flatten()
+            .map(|v| v.abs() as usize),
+        symbols: c.get("symbols").map(|v| v.as_bool()).flatten(),
+        allowed_symbols: c
+            .get("allowed_symbols")
+            .map(|v| v.as_str())
+            .flatten()
+            .map(|v| v.to_owned()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(stage: Option<Stage>) -> Report {
+        Report {
+            stage,
+            ..Report::default()
+        }
+    }
+
+    #[test]
+    fn finished_runs_follow_the_stage() {
+        assert_eq!(
+            report(Some(Stage::Confirmed)).outcome(true, false),
+            Outcome::Confirmed
+        );
+        assert_eq!(
+            report(Some(Stage::Submitted)).outcome(false, false),
+            Outcome::Unconfirmed
+        );
+        assert_eq!(
+            report(Some(Stage::LoggedIn)).outcome(false, false),
+            Outcome::Failed
+        );
+        assert_eq!(report(None).outcome(true, false), Outcome::Confirmed);
+        assert_eq!(report(None).outcome(false, false), Outcome::Failed);
+    }
+
+    #[test]
+    fn interrupted_runs_are_failed_only_before_submitting() {
+        assert_eq!(
+            report(Some(Stage::LoggedIn)).outcome(false, true),
+            Outcome::Failed
+        );
+        assert_eq!(report(None).outcome(false, true), Outcome::Unconfirmed);
+        assert_eq!(
+            report(Some(Stage::Submitted)).outcome(false, true),
+            Outcome::Unconfirmed
+        );
+        assert_eq!(
+            report(Some(Stage::Confirmed)).outcome(false, true),
+            Outcome::Unconfirmed
+        );
+    }
+}
@@ -0,0 +1,407 @@
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use serde_json::{json, Map, Value};
+use tempfile::TempDir;
+use which::which;
+
+use crate::config::{BrowserSettings, BrowserType, Configuration};
+use crate::http::parse_http_recipe;
+use crate::recipe::{parse_recipe, read_recipe, recipe_runner, RecipeRunner};
+use crate::report::{read_report, Outcome, Verification};
+use crate::utils::{self, cmd_timeout, create_private_file, DBEntry, LibraryError, RunOutput};
+use crate::webdriver::{self, driver_bin, free_port, SessionPool};
+
+pub const NIGHTWATCH_BIN: &str = "nightwatch";
+/// Environment variable with the path a Nightwatch script writes its report
+/// to, see `report::read_report`.
+pub const RESULT_ENV: &str = "PASSUP_RESULT";
+/// Mode of the private temp dir, only the user may read the configuration
+/// and the reports in it.
+const TEMP_DIR_MODE: u32 = 0o700;
+const CONFIG_FILE: &str = "nightwatch.conf.js";
+/// Nightwatch scripts, e.g. `scripts/github.com.js`.
+pub const NIGHTWATCH_EXTENSION: &str = "js";
+/// Login-only scripts are named after the site with this suffix, e.g.
+/// `scripts/github.com.login.toml`.
+pub const LOGIN_SUFFIX: &str = ".login";
+/// Recipes executed by the built-in WebDriver client, e.g.
+/// `scripts/github.com.toml`.
+pub const RECIPE_EXTENSION: &str = "toml";
+/// Script types in the order they are looked up in a script dir.
+pub const SCRIPT_EXTENSIONS: [&str; 2] = [RECIPE_EXTENSION, NIGHTWATCH_EXTENSION];
+
+type Result<T, E = utils::Error> = result::Result<T, E>;
+
+/// Executes the script of a site for one entry.
+pub trait Runner: Send + Sync {
+    fn run(&self, script_path: &str, db_entry: &DBEntry) -> Result<RunOutput>;
+}
+
+/// Runs a Nightwatch script in its own `nightwatch` process, which starts a
+/// browser and driver for every entry. The process and everything it started
+/// is killed after `timeout`. The Nightwatch configuration is generated from
+/// the `[browser]` settings and written to a private temp dir with the first
+/// script, the scripts write their reports to the same dir. The dir is
+/// removed with the runner.
+pub struct NightwatchRunner {
+    browser_type: String,
+    timeout: Duration,
+    config: String,
+    temp_dir: Mutex<Option<TempDir>>,
+}
+
+impl NightwatchRunner {
+    pub fn new(browser_type: &BrowserType, browser: &BrowserSettings, timeout: Duration) -> Self {
+        Self {
+            browser_type: browser_type.to_string(),
+            timeout,
+            config: nightwatch_config(browser_type, browser),
+            temp_dir: Mutex::new(None),
+        }
+    }
+
+    /// The private temp dir, created together with the configuration.
+    fn temp_dir(&self) -> Result<PathBuf> {
+        let mut temp_dir = self.temp_dir.lock().unwrap();
+        if let Some(dir) = temp_dir.as_ref() {
+            return Ok(dir.path().to_owned());
+        }
+        let dir = tempfile::Builder::new()
+            .prefix("passup-")
+            .permissions(fs::Permissions::from_mode(TEMP_DIR_MODE))
+            .tempdir()
+            .map_err(|source| utils::Error::TempDirError {
+                source: LibraryError::IoError { source },
+            })?;
+        let file = dir.path().join(CONFIG_FILE);
+        create_private_file(&file)
+            .and_then(|mut f| f.write_all(self.config.as_bytes()))
+            .map_err(|source| utils::Error::NightwatchConfigError {
+                file: file.to_string_lossy().into_owned(),
+                source: LibraryError::IoError { source },
+            })?;
+        let path = dir.path().to_owned();
+        *temp_dir = Some(dir);
+        Ok(path)
+    }
+}
+
+/// Create a file only the user can read, which must not exist yet.
+/// The Nightwatch configuration for the browser as a JavaScript module. The
+/// driver is the configured one, the one in the PATH or the npm package of
+/// the working directory. The port of the driver is set per script with the
+/// `PORT` environment variable.
+fn nightwatch_config(browser_type: &BrowserType, browser: &BrowserSettings) -> String {
+    let driver = driver_bin(browser_type);
+    let server_path = match browser.driver_path(browser_type) {
+        Some(path) => path.to_owned(),
+        None => which(driver)
+            .map(|path| path.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    let args = browser.browser_args(browser_type);
+    let capabilities = match browser_type {
+        BrowserType::Firefox => json!({
+            "browserName": "firefox",
+            "acceptSslCerts": true,
+            "alwaysMatch": { "moz:firefoxOptions": { "args": args } },
+        }),
+        BrowserType::Chrome => json!({
+            "browserName": "chrome",
+            "chromeOptions": { "args": args },
+        }),
+    };
+    let mut test_settings = Map::new();
+    test_settings.insert(
+        browser_type.to_string(),
+        json!({
+            "desiredCapabilities": capabilities,
+            "webdriver": { "start_process": true, "server_path": server_path },
+        }),
+    );
+    let config = json!({
+        "output_folder": browser.output_folder,
+        "test_settings": Value::Object(test_settings),
+    });
+
+    format!(
+        "// Generated by PassUp from the [browser] settings\n\
+         const config = {config};\n\
+         const webdriver = config.test_settings['{browser}'].webdriver;\n\
+         webdriver.port = process.env.PORT;\n\
+         if (!webdriver.server_path) {{\n  \
+           try {{\n    \
+             webdriver.server_path = require(require.resolve('{driver}', {{ paths: [process.cwd()] }})).path;\n  \
+           }} catch (err) {{}}\n\
+         }}\n\
+         module.exports = config;\n",
+        config = serde_json::to_string_pretty(&config).unwrap_or_default(),
+        browser = browser_type,
+        driver = driver,
+    )
+}
+
+impl Runner for NightwatchRunner {
+    fn run(&self, script_path: &str, db_entry: &DBEntry) -> Result<RunOutput> {
+        let temp_dir = self.temp_dir()?;
+        let config_file = temp_dir.join(CONFIG_FILE).to_string_lossy().into_owned();
+        let port = free_port().map_err(|source| utils::Error::CmdError {
+            program: NIGHTWATCH_BIN,
+            args: String::new(),
+            source: LibraryError::IoError { source },
+        })?;
+        let result_path = result_path(&temp_dir)?;
+        let output = cmd_timeout(
+            NIGHTWATCH_BIN,
+            &[
+                "--config",
+                &config_file,
+                "--env",
+                &self.browser_type,
+                "--test",
+                script_path,
+                &db_entry.username,
+                &db_entry.old_password,
+                &db_entry.new_password,
+            ],
+            &[("PORT", &port.to_string()), (RESULT_ENV, &result_path)],
+            self.timeout,
+        );
+        let report = read_report(&result_path);
+        let _ = fs::remove_file(&result_path);
+
+        let (output, timed_out) = output?;
+        let mut output = RunOutput::from(output);
+        output.report = report;
+        if timed_out {
+            output
+                .log
+                .push_str(&format!("Timed out after {}s\n", self.timeout.as_secs()));
+        }
+        Ok(output)
+    }
+}
+
+/// Create an empty file in the private dir, unique per job, where a script
+/// can write its report. The file is created before the script starts, so no
+/// one else can take its place.
+fn result_path(dir: &Path) -> Result<String> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let path = dir
+        .join(format!(
+            "result-{}.json",
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+        .to_string_lossy()
+        .into_owned();
+    create_private_file(Path::new(&path)).map_err(|source| utils::Error::ResultFileError {
+        file: path.to_owned(),
+        source: LibraryError::IoError { source },
+    })?;
+    Ok(path)
+}
+
+/// Runs recipes with the built-in WebDriver client. Up to `nr_sessions`
+/// drivers are started, each with a session that is reset and reused for
+/// the following entries.
+pub struct WebDriverRunner {
+    timeout: Duration,
+    pool: SessionPool,
+}
+
+impl WebDriverRunner {
+    pub fn new(
+        browser_type: &BrowserType,
+        browser: &BrowserSettings,
+        nr_sessions: usize,
+        timeout: Duration,
+    ) -> Self {
+        let driver = browser
+            .driver_path(browser_type)
+            .unwrap_or_else(|| driver_bin(browser_type));
+        Self {
+            timeout,
+            pool: SessionPool::new(
+                browser_type,
+                driver,
+                browser.browser_args(browser_type),
+                nr_sessions,
+            ),
+        }
+    }
+}
+
+impl Runner for WebDriverRunner {
+    fn run(&self, script_path: &str, db_entry: &DBEntry) -> Result<RunOutput> {
+        let recipe = parse_recipe(script_path).map_err(|source| utils::Error::RecipeError {
+            source: LibraryError::RecipeLibError { source },
+        })?;
+        let slot = self.pool.get().map_err(webdriver_error)?;
+        let output = recipe.run(slot.session(), db_entry, self.timeout);
+        self.pool.put(slot);
+        Ok(output)
+    }
+}
+
+/// Runs recipes with `runner = "http"` without a browser, every entry gets
+/// its own cookie jar.
+pub struct HttpRunner {
+    timeout: Duration,
+}
+
+impl HttpRunner {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl Runner for HttpRunner {
+    fn run(&self, script_path: &str, db_entry: &DBEntry) -> Result<RunOutput> {
+        let recipe =
+            parse_http_recipe(script_path).map_err(|source| utils::Error::RecipeError {
+                source: LibraryError::HttpLibError { source },
+            })?;
+        Ok(recipe.run(db_entry, self.timeout))
+    }
+}
+
+/// Log in with `password` using the login script of the site. Login scripts
+/// get the password as `{password}` or `{old_password}`.
+pub fn login(
+    runners: &Runners,
+    login_path: &str,
+    db_entry: &DBEntry,
+    password: &str,
+) -> Result<RunOutput> {
+    let mut entry = db_entry.clone();
+    entry.old_password = password.to_owned();
+    entry.new_password = password.to_owned();
+    runners.get(login_path).run(login_path, &entry)
+}
+
+/// After a change which was not reported as failed, find out which password
+/// the site really uses: log in with the new password and, if that fails,
+/// with the old one.
+pub fn verify(runners: &Runners, login_path: &str, db_entry: &DBEntry, output: &mut RunOutput) {
+    if output.outcome() == Outcome::Failed {
+        return;
+    }
+
+    let verification = match login(runners, login_path, db_entry, &db_entry.new_password) {
+        Ok(login) if login.logged_in() => Verification::NewPassword,
+        Ok(_) => match login(runners, login_path, db_entry, &db_entry.old_password) {
+            Ok(login) if login.logged_in() => Verification::OldPassword,
+            _ => Verification::Neither,
+        },
+        Err(err) => {
+            output
+                .log
+                .push_str(&format!("Verification could not be executed: {}\n", err));
+            return;
+        }
+    };
+    output
+        .log
+        .push_str(&format!("Verification: {}\n", verification));
+    output.verification = Some(verification);
+}
+
+fn webdriver_error(source: webdriver::Error) -> utils::Error {
+    utils::Error::WebDriverError {
+        source: LibraryError::WebDriverLibError { source },
+    }
+}
+
+/// All runners of an update run, the runner of a script is chosen by its
+/// file extension and for recipes by their `runner`.
+pub struct Runners {
+    nightwatch: NightwatchRunner,
+    webdriver: WebDriverRunner,
+    http: HttpRunner,
+}
+
+impl Runners {
+    pub fn new(config: &Configuration) -> Self {
+        Self {
+            nightwatch: NightwatchRunner::new(
+                &config.browser_type,
+                &config.browser,
+                config.jobs.timeout,
+            ),
+            webdriver: WebDriverRunner::new(
+                &config.browser_type,
+                &config.browser,
+                config.nr_threads,
+                config.jobs.timeout,
+            ),
+            http: HttpRunner::new(config.jobs.timeout),
+        }
+    }
+
+    pub fn get(&self, script_path: &str) -> &dyn Runner {
+        let extension = Path::new(script_path)
+            .extension()
+            .map(|e| e.to_str())
+            .flatten();
+        if extension != Some(RECIPE_EXTENSION) {
+            return &self.nightwatch;
+        }
+        // Broken recipes are reported by the WebDriver runner
+        match get_recipe_runner(script_path) {
+            Some(RecipeRunner::Http) => &self.http,
+            _ => &self.webdriver,
+        }
+    }
+}
+
+pub fn get_recipe_runner(script_path: &str) -> Option<RecipeRunner> {
+    read_recipe(script_path)
+        .and_then(|recipe| recipe_runner(&recipe, script_path))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::PRIVATE_FILE_MODE;
+
+    #[test]
+    fn temp_files_are_private() {
+        let runner = NightwatchRunner::new(
+            &BrowserType::Firefox,
+            &BrowserSettings {
+                headless: true,
+                args: Vec::new(),
+                geckodriver: None,
+                chromedriver: None,
+                window_size: None,
+                output_folder: String::new(),
+            },
+            Duration::from_secs(1),
+        );
+        let dir = runner.temp_dir().unwrap();
+        assert_eq!(runner.temp_dir().unwrap(), dir);
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&dir), TEMP_DIR_MODE);
+
+        let config_file = dir.join(CONFIG_FILE);
+        assert_eq!(mode(&config_file), PRIVATE_FILE_MODE);
+        assert_eq!(fs::read_to_string(&config_file).unwrap(), runner.config);
+
+        let first = result_path(&dir).unwrap();
+        let second = result_path(&dir).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(mode(Path::new(&first)), PRIVATE_FILE_MODE);
+        assert_eq!(fs::read(&first).unwrap(), b"");
+
+        drop(runner);
+        assert!(!dir.exists());
+    }
+}
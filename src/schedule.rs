@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use crate::config::Schedule;
+use crate::runner::LOGIN_SUFFIX;
+
+/// Caps how many jobs start per minute, over all domains.
+pub struct RateLimiter {
+    interval: Option<Duration>,
+    next_start: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    /// No limit if `max_starts_per_minute` is 0.
+    pub fn new(max_starts_per_minute: u32) -> Self {
+        let interval = match max_starts_per_minute {
+            0 => None,
+            starts => Some(Duration::from_secs(60) / starts),
+        };
+        Self {
+            interval,
+            next_start: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Block until the next job may start.
+    pub fn wait(&self) {
+        let interval = match self.interval {
+            Some(interval) => interval,
+            None => return,
+        };
+        let start = {
+            let mut next_start = self.next_start.lock().unwrap();
+            let start = (*next_start).max(Instant::now());
+            *next_start = start + interval;
+            start
+        };
+        let now = Instant::now();
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
+
+/// Time to wait between two jobs on the same domain, the minimum delay plus
+/// a random jitter.
+pub fn domain_delay(schedule: &Schedule) -> Duration {
+    let jitter_ms = schedule.domain_jitter.as_millis() as u64;
+    let jitter = match jitter_ms {
+        0 => 0,
+        _ => rand::thread_rng().gen_range(0..=jitter_ms),
+    };
+    schedule.domain_delay + Duration::from_millis(jitter)
+}
+
+/// The domain a script targets, scripts are named after it, e.g.
+/// `scripts/github.com.toml` and `scripts/github.com.login.js` both target
+/// `github.com`.
+pub fn script_domain(script_path: &str) -> String {
+    let stem = Path::new(script_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    stem.trim_end_matches(LOGIN_SUFFIX).to_owned()
+}
+
+/// Group the jobs by their domain, keeping the order of the entries.
+pub fn group_by_domain<T>(jobs: Vec<(String, T)>) -> Vec<(String, Vec<T>)> {
+    let mut indices: HashMap<String, usize> = HashMap::new();
+    let mut groups: Vec<(String, Vec<T>)> = Vec::new();
+    for (domain, job) in jobs {
+        match indices.get(&domain) {
+            Some(&i) => groups[i].1.push(job),
+            None => {
+                indices.insert(domain.to_owned(), groups.len());
+                groups.push((domain, vec![job]));
+            }
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_spaces_the_starts() {
+        // 600 starts per minute are one every 100ms
+        let limiter = RateLimiter::new(600);
+        let start = Instant::now();
+        for _ in 0..4 {
+            limiter.wait();
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(300));
+        assert!(elapsed < Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn rate_limiter_without_cap_does_not_wait() {
+        let limiter = RateLimiter::new(0);
+        let start = Instant::now();
+        for _ in 0..100 {
+            limiter.wait();
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn domain_delay_stays_within_the_jitter() {
+        let delay = Duration::from_millis(200);
+        let jitter = Duration::from_millis(50);
+        let schedule = Schedule::new(delay, jitter, 0);
+        for _ in 0..100 {
+            let d = domain_delay(&schedule);
+            assert!(d >= delay && d <= delay + jitter);
+        }
+        let schedule = Schedule::new(delay, Duration::from_secs(0), 0);
+        assert_eq!(domain_delay(&schedule), delay);
+    }
+
+    #[test]
+    fn scripts_target_the_domain_they_are_named_after() {
+        assert_eq!(script_domain("scripts/github.com.toml"), "github.com");
+        assert_eq!(script_domain("scripts/github.com.js"), "github.com");
+        assert_eq!(script_domain("scripts/github.com.login.js"), "github.com");
+        assert_eq!(script_domain("github.com.login.toml"), "github.com");
+    }
+
+    #[test]
+    fn jobs_are_grouped_in_order() {
+        let jobs = vec![
+            ("github.com".to_owned(), 1),
+            ("gitlab.com".to_owned(), 2),
+            ("github.com".to_owned(), 3),
+            ("reddit.com".to_owned(), 4),
+            ("gitlab.com".to_owned(), 5),
+        ];
+        assert_eq!(
+            group_by_domain(jobs),
+            vec![
+                ("github.com".to_owned(), vec![1, 3]),
+                ("gitlab.com".to_owned(), vec![2, 5]),
+                ("reddit.com".to_owned(), vec![4]),
+            ]
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::result;
+use std::sync::mpsc::channel;
+
+use snafu::{ResultExt, Snafu};
+
+use crate::config::Configuration;
+use crate::keyring::{self, SecretItem, SecretService};
+use crate::utils::{self, commit_results, get_pw, run_update_threads, DBEntry, Uuid, DB};
+
+/// Attributes browsers, libsecret's network password schema and `secret-tool`
+/// users save the site of an item under, in the order they are tried.
+const URL_ATTRIBUTES: [&str; 6] = [
+    "url",
+    "origin_url",
+    "signon_realm",
+    "server",
+    "host",
+    "domain",
+];
+const USERNAME_ATTRIBUTES: [&str; 5] = ["username", "username_value", "user", "login", "account"];
+
+#[derive(Debug, Snafu)]
+enum LibraryError {
+    KeyringError { source: keyring::Error },
+    UtilsError { source: utils::Error },
+}
+
+#[derive(Debug, Snafu)]
+enum Error {
+    #[snafu(display("{}", source))]
+    SecretServiceError {
+        source: LibraryError,
+    },
+    #[snafu(display("Entry has wrong uuid type"))]
+    WrongUuidType,
+    #[snafu(display("Secret \'{}\' was modified during the update", item))]
+    EntryChanged {
+        item: String,
+    },
+    LibError {
+        source: LibraryError,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+pub fn run(config: &Configuration) {
+    let blocklist = config
+        .sources
+        .first()
+        .map(|s| s.blocklist.to_owned())
+        .unwrap_or_default();
+    let service = match SecretService::connect() {
+        Ok(service) => service,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+    let db = match parse_items(&service) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            return;
+        }
+    };
+
+    let (tx, rx) = channel();
+    run_update_threads(&db, &blocklist, config, tx);
+
+    commit_results(rx, false, |updated_db| -> Result<Vec<Uuid>> {
+        let mut written = Vec::new();
+        for db_entry in &updated_db.entries {
+            match update_item(&service, db_entry) {
+                Ok(_) => written.push(db_entry.uuid.to_owned()),
+                Err(err) => eprintln!("Error: {}", err),
+            }
+        }
+        Ok(written)
+    });
+}
+
+fn attribute_values(item: &SecretItem, keys: &[&str]) -> Vec<String> {
+    keys.iter()
+        .filter_map(|key| item.attributes.get(*key))
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_owned())
+        .collect()
+}
+
+fn parse_items(service: &SecretService) -> Result<DB> {
+    let items = service
+        .items()
+        .context(KeyringError)
+        .context(SecretServiceError)?;
+
+    let mut db_vec = Vec::new();
+    for item in items {
+        let mut urls = attribute_values(&item, &URL_ATTRIBUTES).into_iter();
+        let username = attribute_values(&item, &USERNAME_ATTRIBUTES)
+            .into_iter()
+            .next();
+        let (url, username) = match (urls.next(), username) {
+            (Some(url), Some(username)) if !item.secret.is_empty() => (url, username),
+            _ => continue,
+        };
+
+        let mut db_entry = DBEntry::new(
+            url,
+            username,
+            item.secret,
+            get_pw().context(UtilsError).context(LibError)?,
+        );
+        db_entry.alt_urls = urls.collect();
+        if !item.label.is_empty() {
+            db_entry.alt_urls.push(item.label);
+        }
+        db_entry.uuid = Uuid::SecretService(item.path);
+        db_vec.push(db_entry);
+    }
+
+    Ok(DB::new(db_vec))
+}
+
+/// Only replace the secret if it still holds the old password.
+fn update_item(service: &SecretService, db_entry: &DBEntry) -> Result<()> {
+    let path = match &db_entry.uuid {
+        Uuid::SecretService(path) => path,
+        _ => return Err(Error::WrongUuidType),
+    };
+
+    let secret = service
+        .get_secret(path)
+        .context(KeyringError)
+        .context(SecretServiceError)?;
+    if secret != db_entry.old_password {
+        return Err(Error::EntryChanged {
+            item: path.to_owned(),
+        });
+    }
+    service
+        .set_secret(path, &db_entry.new_password)
+        .context(KeyringError)
+        .context(SecretServiceError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keyring::mock::{self, MockBus, MockItem};
+
+    fn items() -> Vec<MockItem> {
+        vec![
+            MockItem {
+                label: "GitHub",
+                attributes: vec![
+                    ("origin_url", "https://github.com/"),
+                    ("username_value", "alice"),
+                ],
+                secret: "hunter2",
+            },
+            MockItem {
+                label: "Wi-Fi",
+                attributes: vec![("ssid", "home")],
+                secret: "wifi-password",
+            },
+        ]
+    }
+
+    #[test]
+    fn items_without_username_are_skipped() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        mock::secret_service(&bus, items());
+        let service = SecretService::connect().unwrap();
+        let db = parse_items(&service).unwrap();
+        assert_eq!(db.entries.len(), 1);
+        let entry = &db.entries[0];
+        assert_eq!(entry.url, "https://github.com/");
+        assert_eq!(entry.username, "alice");
+        assert_eq!(entry.old_password, "hunter2");
+        assert_eq!(entry.alt_urls, vec!["GitHub"]);
+    }
+
+    #[test]
+    fn secret_is_updated() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let secrets = mock::secret_service(&bus, items());
+        let service = SecretService::connect().unwrap();
+        let db = parse_items(&service).unwrap();
+        update_item(&service, &db.entries[0]).unwrap();
+        let path = match &db.entries[0].uuid {
+            Uuid::SecretService(path) => path.to_owned(),
+            _ => unreachable!(),
+        };
+        assert_eq!(secrets.lock().unwrap()[&path], db.entries[0].new_password);
+    }
+
+    #[test]
+    fn changed_secret_is_kept() {
+        let bus = match MockBus::start() {
+            Some(bus) => bus,
+            None => return,
+        };
+        let secrets = mock::secret_service(&bus, items());
+        let service = SecretService::connect().unwrap();
+        let db = parse_items(&service).unwrap();
+        let path = match &db.entries[0].uuid {
+            Uuid::SecretService(path) => path.to_owned(),
+            _ => unreachable!(),
+        };
+        secrets
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), "changed".to_owned());
+        assert!(matches!(
+            update_item(&service, &db.entries[0]),
+            Err(Error::EntryChanged { .. })
+        ));
+        assert_eq!(secrets.lock().unwrap()[&path], "changed");
+    }
+}
@@ -1,8 +1,15 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
-use std::process::{Command, Output};
-use std::sync::mpsc::Sender;
-use std::{io, net, result, str};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Once};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, io, iter, mem, result, str, thread};
 
 use kpdb::EntryUuid;
 use passwords::PasswordGenerator;
@@ -12,26 +19,57 @@ use threadpool::ThreadPool;
 use url::Url;
 use which::which;
 
-use crate::config::{BrowserType, Configuration};
-use crate::utils;
+use crate::config::{BrowserType, Configuration, JobPolicy, Mode};
+use crate::recipe::RecipeRunner;
+use crate::report::{CredentialState, Outcome, Reason, Report, Stage, Verification};
+use crate::runner::{
+    get_recipe_runner, login, verify, Runners, LOGIN_SUFFIX, NIGHTWATCH_BIN, NIGHTWATCH_EXTENSION,
+    RECIPE_EXTENSION, SCRIPT_EXTENSIONS,
+};
+use crate::schedule::{domain_delay, group_by_domain, script_domain, RateLimiter};
+use crate::webdriver::driver_bin;
+use crate::{http, recipe, utils, webdriver};
 
-const FIREFOX_PORT: u16 = 4444;
-const CHROME_PORT: u16 = 9515;
 const FIREFOX_BIN: &str = "firefox";
 const CHROME_BIN: &str = "google-chrome";
-const NIGHTWATCH_BIN: &str = "nightwatch";
-const LOCALHOST: &str = "127.0.0.1";
+const CMD_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Results arriving within this time of each other are committed together.
+const BATCH_WAIT: Duration = Duration::from_secs(5);
+const BATCH_SIZE: usize = 20;
+/// Mode of the files written with the passwords, only the user may read them.
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+const TMP_SUFFIX: &str = "passup-tmp";
+
+/// Columns of Chrome's primary key of the `logins` table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChromeLoginId {
+    pub origin_url: String,
+    pub username_element: String,
+    pub username_value: String,
+    pub password_element: String,
+    pub signon_realm: String,
+}
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Uuid {
     None,
     Kdbx(EntryUuid),
     Pwsafe([u8; 16]),
+    Pass(String),
+    Firefox(String),
+    Chrome(ChromeLoginId),
+    Bitwarden(String),
+    Csv(usize),
+    OnePassword(String),
+    CredentialFile(usize),
+    SecretService(String),
 }
 
 #[derive(Debug, Clone)]
 pub struct DBEntry {
     pub url: String,
+    /// Further URLs of the entry, tried in order if no script matches `url`.
+    pub alt_urls: Vec<String>,
     pub username: String,
     pub old_password: String,
     pub new_password: String,
@@ -42,6 +80,7 @@ impl DBEntry {
     pub fn new(url: String, username: String, old_password: String, new_password: String) -> Self {
         Self {
             url,
+            alt_urls: Vec::new(),
             username,
             old_password,
             new_password,
@@ -51,6 +90,7 @@ impl DBEntry {
     pub fn empty() -> Self {
         Self {
             url: "".to_owned(),
+            alt_urls: Vec::new(),
             username: "".to_owned(),
             old_password: "".to_owned(),
             new_password: "".to_owned(),
@@ -75,6 +115,10 @@ pub enum LibraryError {
     UrlError { source: url::ParseError },
     IoError { source: io::Error },
     RegexLibError { source: regex::Error },
+    RecipeLibError { source: recipe::Error },
+    HttpLibError { source: http::Error },
+    WebDriverLibError { source: webdriver::Error },
+    CtrlcError { source: ctrlc::Error },
 }
 
 #[derive(Debug, Snafu)]
@@ -111,10 +155,23 @@ pub enum Error {
         path: String,
     },
     ScriptBlocked,
-    #[snafu(display("Warning: Script for website \'{}\' with username: \'{}\' did not execute successfully\n{}", db_entry.url, db_entry.username, str::from_utf8(&output.stdout).unwrap_or("error")))]
-    NightwatchExecError {
+    #[snafu(display("Warning: Script for website \'{}\' with username: \'{}\' did not execute successfully ({})\n{}", db_entry.url, db_entry.username, output.report, output.log))]
+    ScriptExecError {
+        db_entry: DBEntry,
+        output: RunOutput,
+    },
+    #[snafu(display("Error: The new password for website \'{}\' with username: \'{}\' was submitted but not confirmed, the site may already use it. Verify the account manually, new password: {}\n{}", db_entry.url, db_entry.username, db_entry.new_password, output.log))]
+    ScriptUnconfirmedError {
         db_entry: DBEntry,
-        output: Output,
+        output: RunOutput,
+    },
+    #[snafu(display("{}", source))]
+    RecipeError {
+        source: LibraryError,
+    },
+    #[snafu(display("WebDriver error: {}", source))]
+    WebDriverError {
+        source: LibraryError,
     },
     #[snafu(display(
         "The binary {} was not found! Please install {}, refer to the README.md for help",
@@ -130,20 +187,170 @@ pub enum Error {
         expr: String,
         source: LibraryError,
     },
+    #[snafu(display(
+        "Could not write the Nightwatch configuration \'{}\': {}",
+        file,
+        source
+    ))]
+    NightwatchConfigError {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not create a private temp directory: {}", source))]
+    TempDirError {
+        source: LibraryError,
+    },
+    #[snafu(display("Could not create the result file \'{}\': {}", file, source))]
+    ResultFileError {
+        file: String,
+        source: LibraryError,
+    },
+    #[snafu(display("Could not set the Ctrl-C handler: {}", source))]
+    InterruptHandlerError {
+        source: LibraryError,
+    },
 }
 
 type Result<T, E = Error> = result::Result<T, E>;
 
+/// Outcome of a script run, independent of the runner that executed it.
+#[derive(Debug)]
+pub struct RunOutput {
+    pub success: bool,
+    pub log: String,
+    pub report: Report,
+    /// Which password a login after the change succeeded with, if the site
+    /// has a login script.
+    pub verification: Option<Verification>,
+    /// The script timed out or was killed by a signal.
+    pub interrupted: bool,
+}
+
+impl RunOutput {
+    pub fn new(success: bool, log: String, report: Report) -> Self {
+        Self {
+            success,
+            log,
+            report,
+            verification: None,
+            interrupted: false,
+        }
+    }
+
+    /// A verification overrules what the script reported.
+    pub fn outcome(&self) -> Outcome {
+        match self.verification {
+            Some(Verification::NewPassword) => Outcome::Confirmed,
+            Some(Verification::OldPassword) => Outcome::Failed,
+            Some(Verification::Neither) => Outcome::Unconfirmed,
+            None => self.report.outcome(self.success, self.interrupted),
+        }
+    }
+
+    /// Whether a login script got past the login.
+    pub fn logged_in(&self) -> bool {
+        match self.report.stage {
+            Some(stage) => stage >= Stage::LoggedIn,
+            None => self.success,
+        }
+    }
+
+    /// State of the credentials according to a login script, failed logins
+    /// are told apart by their reason.
+    pub fn credential_state(&self) -> CredentialState {
+        if self.logged_in() {
+            return CredentialState::Valid;
+        }
+        match self.report.reason {
+            Some(Reason::InvalidCredentials) => CredentialState::Invalid,
+            Some(Reason::AccountLocked) => CredentialState::Locked,
+            Some(Reason::TwoFactor) => CredentialState::TwoFactor,
+            _ => CredentialState::Unknown,
+        }
+    }
+
+    /// Whether the site confirmed the new password, only then it may be
+    /// written to the database.
+    pub fn confirmed(&self) -> bool {
+        self.outcome() == Outcome::Confirmed
+    }
+
+    /// Whether a failed run can be repeated safely: it was interrupted and
+    /// reported a stage before the new password was submitted.
+    pub fn retryable(&self) -> bool {
+        self.interrupted
+            && self
+                .report
+                .stage
+                .map_or(false, |stage| stage < Stage::Submitted)
+    }
+}
+
+impl From<Output> for RunOutput {
+    fn from(output: Output) -> Self {
+        let mut run_output = Self::new(
+            output.status.success(),
+            str::from_utf8(&output.stdout).unwrap_or("error").to_owned(),
+            Report::default(),
+        );
+        run_output.interrupted = output.status.code().is_none();
+        run_output
+    }
+}
+
+/// The error for an entry whose password was not confirmed, unconfirmed
+/// submissions are reported as errors with the new password.
+pub fn script_error(db_entry: DBEntry, output: RunOutput) -> Error {
+    match output.outcome() {
+        Outcome::Unconfirmed => Error::ScriptUnconfirmedError { db_entry, output },
+        _ => Error::ScriptExecError { db_entry, output },
+    }
+}
+
 pub struct ThreadResult {
     pub db_entry: DBEntry,
-    pub result: Result<Output, utils::Error>,
+    pub result: Result<RunOutput, utils::Error>,
 }
 impl ThreadResult {
-    fn new(db_entry: DBEntry, result: Result<Output, utils::Error>) -> Self {
+    fn new(db_entry: DBEntry, result: Result<RunOutput, utils::Error>) -> Self {
         Self { db_entry, result }
     }
 }
 
+/// Create a file only the user can read, which must not exist yet.
+pub fn create_private_file(file: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(PRIVATE_FILE_MODE)
+        .open(file)
+}
+
+/// Path of the temporary file a file is written to before it is renamed over
+/// the original.
+pub fn tmp_path(file: &Path) -> PathBuf {
+    let mut tmp_file = file.as_os_str().to_owned();
+    tmp_file.push(".");
+    tmp_file.push(TMP_SUFFIX);
+    PathBuf::from(tmp_file)
+}
+
+/// Replace `file` with `content` readable only by the user. The content is
+/// written to a new temporary file next to it, synced and renamed over the
+/// file, so a crash leaves either the old or the new content and an existing
+/// file does not keep looser permissions.
+pub fn write_private_file(file: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp_file = tmp_path(file);
+    let _ = fs::remove_file(&tmp_file);
+    create_private_file(&tmp_file)
+        .and_then(|mut f| f.write_all(content).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&tmp_file, file))
+        .map_err(|err| {
+            let _ = fs::remove_file(&tmp_file);
+            err
+        })
+}
+
 pub fn get_pw() -> Result<String> {
     let pass_gen = PasswordGenerator {
         length: 16,
@@ -160,43 +367,168 @@ pub fn get_pw() -> Result<String> {
         .map_err(|err| Error::PasswordGeneratorError { err })
 }
 
-pub fn cmd(program: &'static str, args: &[&str], port: &str) -> Result<Output> {
-    let args_v = args.to_owned();
-    let mut args_s = String::new();
-    for arg in args_v {
-        args_s.push_str(&format!(" {}", arg));
-    }
-    return Command::new(program)
+/// Execute the program in its own process group, which is killed with
+/// everything the program started once `timeout` has passed. Returns whether
+/// it timed out. Its own process group also keeps Ctrl-C in the terminal from
+/// reaching it.
+pub fn cmd_timeout(
+    program: &'static str,
+    args: &[&str],
+    envs: &[(&str, &str)],
+    timeout: Duration,
+) -> Result<(Output, bool)> {
+    let args_s: String = args.iter().map(|arg| format!(" {}", arg)).collect();
+    let cmd_error = |source| Error::CmdError {
+        program,
+        args: args_s.to_owned(),
+        source: LibraryError::IoError { source },
+    };
+    let mut child = Command::new(program)
         .args(args)
-        .env("PORT", port)
-        .output()
-        .context(IoError)
-        .context(CmdError {
-            program,
-            args: args_s,
-        });
+        .envs(envs.iter().cloned())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()
+        .map_err(cmd_error)?;
+    track_process_group(child.id());
+    let stdout = read_pipe(child.stdout.take());
+    let stderr = read_pipe(child.stderr.take());
+
+    let start = Instant::now();
+    let mut timed_out = false;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Ok(status),
+            Ok(None) => (),
+            Err(err) => break Err(err),
+        }
+        if start.elapsed() >= timeout {
+            timed_out = true;
+            kill_process_group(child.id());
+            break child.wait();
+        }
+        thread::sleep(CMD_POLL_INTERVAL);
+    };
+    untrack_process_group(child.id());
+    let status = status.map_err(cmd_error)?;
+
+    let output = Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    };
+    Ok((output, timed_out))
 }
 
-//pub fn exec_nightwatch(script_path: &str, url: &str, db_entry: &DBEntry, browser_type: &String, port: &String) -> Result<Output> {
-pub fn exec_nightwatch(
-    script_path: &str,
-    db_entry: &DBEntry,
-    browser_type: &str,
-    port: &str,
-) -> Result<Output> {
-    cmd(
-        NIGHTWATCH_BIN,
-        &[
-            "--env",
-            browser_type,
-            "--test",
-            script_path,
-            &db_entry.username,
-            &db_entry.old_password,
-            &db_entry.new_password,
-        ],
-        port,
-    )
+fn read_pipe<R: Read + Send + 'static>(pipe: Option<R>) -> thread::JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+/// Process groups of the scripts and drivers running right now, killed with
+/// everything they started when PassUp exits on a second Ctrl-C.
+static PROCESS_GROUPS: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+pub fn track_process_group(pgid: u32) {
+    PROCESS_GROUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .push(pgid);
+}
+
+pub fn untrack_process_group(pgid: u32) {
+    PROCESS_GROUPS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .retain(|&p| p != pgid);
+}
+
+fn kill_process_group(pgid: u32) {
+    // A negative pid addresses the whole process group
+    unsafe {
+        libc::kill(-(pgid as libc::pid_t), libc::SIGKILL);
+    }
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static INTERRUPT_HANDLER: Once = Once::new();
+
+/// On the first Ctrl-C no new jobs are started, jobs already running finish
+/// and their results are written. A second Ctrl-C kills the running scripts
+/// and drivers and exits immediately. The handler is installed once the jobs
+/// start, after all password prompts, so Ctrl-C at a prompt still exits
+/// PassUp the usual way and restores the terminal.
+fn handle_interrupt() {
+    INTERRUPT_HANDLER.call_once(|| {
+        if let Err(err) = set_interrupt_handler() {
+            eprintln!("Warning: {}", err);
+        }
+    });
+}
+
+fn set_interrupt_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            let process_groups = PROCESS_GROUPS.lock().unwrap_or_else(|err| err.into_inner());
+            for &pgid in process_groups.iter() {
+                kill_process_group(pgid);
+            }
+            process::exit(130);
+        }
+        eprintln!(
+            "Interrupted: no new jobs are started, waiting for the running ones to finish. Press Ctrl-C again to exit immediately"
+        );
+    })
+    .map_err(|source| Error::InterruptHandlerError {
+        source: LibraryError::CtrlcError { source },
+    })
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Run a job and repeat it with exponential backoff as long as it fails
+/// transiently: the script could not be executed or timed out after it
+/// logged in, but before it submitted the new password.
+fn run_with_retries<F>(jobs: &JobPolicy, db_entry: &DBEntry, run: F) -> Result<RunOutput>
+where
+    F: Fn() -> Result<RunOutput>,
+{
+    let mut delay = jobs.retry_delay;
+    let mut attempt = 0;
+    loop {
+        let result = run();
+        let retryable = match &result {
+            Ok(output) => output.retryable(),
+            Err(Error::CmdError { .. }) | Err(Error::WebDriverError { .. }) => true,
+            Err(_) => false,
+        };
+        if !retryable || attempt >= jobs.retries || interrupted() {
+            return result;
+        }
+
+        attempt += 1;
+        eprintln!(
+            "Warning: Retrying website {}, with username {} in {}s ({}/{})",
+            db_entry.url,
+            db_entry.username,
+            delay.as_secs(),
+            attempt,
+            jobs.retries
+        );
+        thread::sleep(delay);
+        if interrupted() {
+            return result;
+        }
+        delay *= 2;
+    }
 }
 
 fn get_url_check_source_blocklist(
@@ -244,46 +576,130 @@ fn get_url_check_source_blocklist(
     Ok(url)
 }
 
-pub fn get_url_and_script_path(
+/// Look up `<url><suffix>.toml` and then `<url><suffix>.js` in each script
+/// dir. Blocking either name of a site's change script blocks all its
+/// scripts.
+fn get_script_path(
     config: &Configuration,
-    blocklist: &[String],
+    url: &str,
+    suffix: &str,
     db_entry: &DBEntry,
 ) -> Result<String> {
     let mut path = String::new();
     for script in config.scripts.iter() {
-        let mut script_path = PathBuf::new();
-        script_path.push(&script.dir);
-
-        let url = get_url_check_source_blocklist(&db_entry.url, blocklist, &config.urls)?;
-        let script_name = format!("{}.js", url);
-
-        script_path.push(&script_name);
-        path = script_path
-            .to_str()
-            .ok_or(Error::ScriptPathError {
-                url: db_entry.url.to_owned(),
-            })?
-            .to_owned();
-
-        if !script_path.exists() {
-            continue;
-        }
+        for extension in SCRIPT_EXTENSIONS.iter() {
+            let mut script_path = PathBuf::new();
+            script_path.push(&script.dir);
 
-        if script.blocklist.contains(&script_name) {
-            return Err(Error::ScriptBlocked);
+            let script_name = format!("{}{}.{}", url, suffix, extension);
+
+            script_path.push(&script_name);
+            path = script_path
+                .to_str()
+                .ok_or(Error::ScriptPathError {
+                    url: db_entry.url.to_owned(),
+                })?
+                .to_owned();
+
+            if !script_path.exists() {
+                continue;
+            }
+
+            let blocked = SCRIPT_EXTENSIONS
+                .iter()
+                .any(|ext| script.blocklist.contains(&format!("{}.{}", url, ext)));
+            if blocked {
+                return Err(Error::ScriptBlocked);
+            }
+            return Ok(path);
         }
-        return Ok(path);
     }
     Err(Error::ScriptMissingError { path })
 }
 
-pub fn check_dependencies(config: &Configuration) -> Result<()> {
-    if which(NIGHTWATCH_BIN).is_err() {
+/// Resolve the script for the first URL of the entry that has one, starting
+/// with `url` and then trying `alt_urls`. An entry is skipped as soon as one
+/// of its URLs is on the blocklist.
+pub fn get_url_and_script_path(
+    config: &Configuration,
+    blocklist: &[String],
+    db_entry: &DBEntry,
+) -> Result<String> {
+    find_script(config, blocklist, db_entry, "")
+}
+
+/// Resolve the login-only script of the entry, e.g. `github.com.login.toml`.
+pub fn get_login_script_path(
+    config: &Configuration,
+    blocklist: &[String],
+    db_entry: &DBEntry,
+) -> Result<String> {
+    find_script(config, blocklist, db_entry, LOGIN_SUFFIX)
+}
+
+fn find_script(
+    config: &Configuration,
+    blocklist: &[String],
+    db_entry: &DBEntry,
+    suffix: &str,
+) -> Result<String> {
+    let mut last_err = None;
+    let urls = std::iter::once(&db_entry.url).chain(db_entry.alt_urls.iter());
+    for entry_url in urls.filter(|url| !url.is_empty()) {
+        let url = match get_url_check_source_blocklist(entry_url, blocklist, &config.urls) {
+            Ok(url) => url,
+            Err(Error::UrlDomainBlocked) => return Err(Error::UrlDomainBlocked),
+            Err(err) => {
+                last_err.get_or_insert(err);
+                continue;
+            }
+        };
+        match get_script_path(config, &url, suffix, db_entry) {
+            Err(err @ Error::ScriptMissingError { .. }) => {
+                last_err.get_or_insert(err);
+            }
+            result => return result,
+        }
+    }
+    Err(last_err.unwrap_or(Error::UrlDomainError {
+        url: db_entry.url.to_owned(),
+    }))
+}
+
+/// Check the programs the scripts selected for a run need: Nightwatch for
+/// `.js` scripts, the driver for WebDriver recipes and the browser for both.
+/// HTTP recipes need none of them.
+fn check_dependencies(config: &Configuration, script_paths: &[&str]) -> Result<()> {
+    let has_extension = |path: &str, extension: &str| {
+        Path::new(path).extension().map(|e| e.to_str()).flatten() == Some(extension)
+    };
+    let nightwatch = script_paths
+        .iter()
+        .any(|path| has_extension(path, NIGHTWATCH_EXTENSION));
+    let webdriver = script_paths.iter().any(|path| {
+        has_extension(path, RECIPE_EXTENSION) && get_recipe_runner(path) != Some(RecipeRunner::Http)
+    });
+
+    if nightwatch && which(NIGHTWATCH_BIN).is_err() {
         return Err(Error::DependencyMissingError {
             binary_name: NIGHTWATCH_BIN,
             program: "Nightwatch",
         });
     }
+    let driver = driver_bin(&config.browser_type);
+    let driver_path = config
+        .browser
+        .driver_path(&config.browser_type)
+        .unwrap_or(driver);
+    if webdriver && which(driver_path).is_err() {
+        return Err(Error::DependencyMissingError {
+            binary_name: driver,
+            program: "WebDriver",
+        });
+    }
+    if !nightwatch && !webdriver {
+        return Ok(());
+    }
     if config.browser_type == BrowserType::Firefox {
         if which(FIREFOX_BIN).is_err() {
             return Err(Error::DependencyMissingError {
@@ -301,27 +717,22 @@ pub fn check_dependencies(config: &Configuration) -> Result<()> {
     Ok(())
 }
 
-pub fn check_port_available(port: u16) -> bool {
-    net::TcpListener::bind((LOCALHOST, port)).is_ok()
-}
-
+/// Start the update jobs and return, the results are sent as the jobs finish
+/// and the channel is closed after the last one. Jobs skipped after Ctrl-C
+/// send no result. In check mode no password is changed, the credentials are
+/// only checked and no results are sent.
 pub fn run_update_threads(
     db: &DB,
     blocklist: &[String],
     config: &Configuration,
     tx: Sender<ThreadResult>,
-) -> usize {
-    let mut port;
-    let browser_type;
-    if config.browser_type == BrowserType::Firefox {
-        port = FIREFOX_PORT;
-        browser_type = BrowserType::Firefox.to_string();
-    } else {
-        port = CHROME_PORT;
-        browser_type = BrowserType::Chrome.to_string();
+) {
+    if config.mode == Mode::Check {
+        run_check_threads(db, blocklist, config);
+        return;
     }
-    let mut nr_jobs = 0usize;
-    let pool = ThreadPool::new(config.nr_threads);
+
+    let mut jobs = Vec::new();
     for db_entry in db.entries.iter() {
         let entry = db_entry.clone();
         let script_path = match get_url_and_script_path(config, blocklist, db_entry) {
@@ -334,25 +745,406 @@ pub fn run_update_threads(
             }
         };
 
-        let browser_type_ = browser_type.clone();
+        let login_path = get_login_script_path(config, blocklist, db_entry).ok();
+        jobs.push((
+            script_domain(&script_path),
+            (entry, script_path, login_path),
+        ));
+    }
+    let script_paths = jobs
+        .iter()
+        .flat_map(|(_, (_, script_path, login_path))| {
+            iter::once(script_path.as_str()).chain(login_path.as_deref())
+        })
+        .collect::<Vec<_>>();
+    if let Err(err) = check_dependencies(config, &script_paths) {
+        eprintln!("Dependency Error: {}", err);
+        return;
+    }
+
+    let runners = Arc::new(Runners::new(config));
+    let policy = config.jobs.clone();
+    let pool = ThreadPool::new(config.nr_threads);
+    handle_interrupt();
+    run_scheduled(
+        &pool,
+        config,
+        jobs,
+        move |(entry, script_path, login_path)| {
+            let mut result = run_with_retries(&policy, &entry, || {
+                runners.get(&script_path).run(&script_path, &entry)
+            });
+            if let (Ok(output), Some(login_path)) = (result.as_mut(), login_path) {
+                verify(&runners, &login_path, &entry, output);
+            }
+            tx.send(ThreadResult::new(entry, result))
+                .expect("Error: Thread could not send");
+        },
+    );
+}
 
-        nr_jobs += 1;
-        let tx = tx.clone();
+/// Receive the results of the update jobs and hand the confirmed entries to
+/// `commit` as they arrive, so a late crash or write error does not lose the
+/// passwords already changed. With `batched` the entries arriving within
+/// `BATCH_WAIT` of each other are committed together, for databases which
+/// are re-encrypted as a whole on every write. `commit` returns the uuids of
+/// the entries it wrote, the new passwords of all others are printed.
+pub fn commit_results<F, E>(rx: Receiver<ThreadResult>, batched: bool, mut commit: F)
+where
+    F: FnMut(&DB) -> Result<Vec<Uuid>, E>,
+    E: fmt::Display,
+{
+    let mut pending = Vec::new();
+    loop {
+        let received = if pending.is_empty() {
+            rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(BATCH_WAIT)
+        };
+        let thread_result = match received {
+            Ok(thread_result) => thread_result,
+            Err(RecvTimeoutError::Timeout) => {
+                commit_entries(&mut pending, &mut commit);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        match thread_result.result {
+            Ok(output) if output.confirmed() => pending.push(thread_result.db_entry),
+            Ok(output) => eprintln!("{}", script_error(thread_result.db_entry, output)),
+            Err(err) => eprintln!("Error while executing script: {}", err),
+        }
+        if !batched || pending.len() >= BATCH_SIZE {
+            commit_entries(&mut pending, &mut commit);
+        }
+    }
+    commit_entries(&mut pending, &mut commit);
+}
+
+fn commit_entries<F, E>(pending: &mut Vec<DBEntry>, commit: &mut F)
+where
+    F: FnMut(&DB) -> Result<Vec<Uuid>, E>,
+    E: fmt::Display,
+{
+    if pending.is_empty() {
+        return;
+    }
+    let db = DB::new(mem::take(pending));
+    let written = match commit(&db) {
+        Ok(written) => written,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            Vec::new()
+        }
+    };
+    for db_entry in &db.entries {
+        if written.contains(&db_entry.uuid) {
+            println!(
+                "Updated password on website {}, with username {}",
+                db_entry.url, db_entry.username
+            );
+        } else {
+            eprintln!("{}", not_written_error(db_entry));
+        }
+    }
+}
+
+/// The new password of an entry which could not be written, so the user can
+/// still store it by hand.
+fn not_written_error(db_entry: &DBEntry) -> String {
+    format!(
+        "Error: The new password for website \'{}\' with username: \'{}\' was not written, new password: {}",
+        db_entry.url, db_entry.username, db_entry.new_password
+    )
+}
+
+/// Run the jobs of every domain one after another on the pool, with a delay
+/// between two jobs on the same domain and the start rate capped over all
+/// domains. No further jobs are started after Ctrl-C.
+fn run_scheduled<T, F>(
+    pool: &ThreadPool,
+    config: &Configuration,
+    jobs: Vec<(String, T)>,
+    run_job: F,
+) where
+    T: Send + 'static,
+    F: Fn(T) + Clone + Send + 'static,
+{
+    let limiter = Arc::new(RateLimiter::new(config.schedule.max_starts_per_minute));
+    for (domain, domain_jobs) in group_by_domain(jobs) {
+        let nr_jobs = domain_jobs.len();
+        let limiter = limiter.clone();
+        let schedule = config.schedule.clone();
+        let run_job = run_job.clone();
         pool.execute(move || {
-            match exec_nightwatch(&script_path, &entry, &browser_type_, &port.to_string()) {
-                Ok(output) => tx
-                    .send(ThreadResult::new(entry, Ok(output)))
-                    .expect("Error: Thread could not send"),
-                Err(err) => tx
-                    .send(ThreadResult::new(entry, Err(err)))
-                    .expect("Error: Thread could not send"),
-            };
+            for (i, job) in domain_jobs.into_iter().enumerate() {
+                if i > 0 && !interrupted() {
+                    thread::sleep(domain_delay(&schedule));
+                }
+                limiter.wait();
+                if interrupted() {
+                    eprintln!(
+                        "Warning: Interrupted, {} entries of {} were not started",
+                        nr_jobs - i,
+                        domain
+                    );
+                    return;
+                }
+                run_job(job);
+            }
         });
-        port += 1;
-        while !check_port_available(port) {
-            port += 1;
-        }
+    }
+}
+
+/// Log in to the site of every entry with its stored password and report
+/// whether the credentials are valid, invalid, locked or need 2FA.
+fn run_check_threads(db: &DB, blocklist: &[String], config: &Configuration) {
+    let mut jobs = Vec::new();
+    for db_entry in db.entries.iter() {
+        let entry = db_entry.clone();
+        let login_path = match get_login_script_path(config, blocklist, db_entry) {
+            Ok(login_path) => login_path,
+            Err(utils::Error::UrlDomainBlocked) => continue,
+            Err(utils::Error::ScriptBlocked) => continue,
+            Err(err) => {
+                eprintln!("Warning: {}", err);
+                continue;
+            }
+        };
+
+        jobs.push((script_domain(&login_path), (entry, login_path)));
+    }
+    let login_paths = jobs
+        .iter()
+        .map(|(_, (_, login_path))| login_path.as_str())
+        .collect::<Vec<_>>();
+    if let Err(err) = check_dependencies(config, &login_paths) {
+        eprintln!("Dependency Error: {}", err);
+        return;
+    }
+
+    let runners = Arc::new(Runners::new(config));
+    let policy = config.jobs.clone();
+    let pool = ThreadPool::new(config.nr_threads);
+    let (tx, rx) = channel();
+    handle_interrupt();
+    // Skipped jobs send nothing, the results end once all workers are done
+    run_scheduled(&pool, config, jobs, move |(entry, login_path)| {
+        let result = run_with_retries(&policy, &entry, || {
+            login(&runners, &login_path, &entry, &entry.old_password)
+        });
+        tx.send((entry, result))
+            .expect("Error: Thread could not send");
+    });
+
+    let mut states = HashMap::new();
+    let mut nr_checked = 0usize;
+    for (db_entry, result) in rx.iter() {
+        nr_checked += 1;
+        let state = match result {
+            Ok(output) => {
+                let state = output.credential_state();
+                if state == CredentialState::Unknown {
+                    eprintln!("{}", output.log);
+                }
+                state
+            }
+            Err(err) => {
+                eprintln!("Error while executing script: {}", err);
+                CredentialState::Unknown
+            }
+        };
+        println!(
+            "{}: website {}, with username {}",
+            state, db_entry.url, db_entry.username
+        );
+        *states.entry(state).or_insert(0usize) += 1;
     }
     pool.join();
-    nr_jobs
+
+    let count = |state| states.get(&state).copied().unwrap_or(0);
+    println!(
+        "Checked {} entries: {} valid, {} invalid, {} locked, {} need 2FA, {} unknown",
+        nr_checked,
+        count(CredentialState::Valid),
+        count(CredentialState::Invalid),
+        count(CredentialState::Locked),
+        count(CredentialState::TwoFactor),
+        count(CredentialState::Unknown)
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(stage: Option<Stage>, interrupted: bool) -> RunOutput {
+        let mut output = RunOutput::new(
+            false,
+            String::new(),
+            Report {
+                stage,
+                ..Report::default()
+            },
+        );
+        output.interrupted = interrupted;
+        output
+    }
+
+    fn entry(name: &str) -> DBEntry {
+        let mut db_entry = DBEntry::new(
+            format!("{}.com", name),
+            name.to_owned(),
+            "old".to_owned(),
+            format!("new-{}", name),
+        );
+        db_entry.uuid = Uuid::Pass(name.to_owned());
+        db_entry
+    }
+
+    fn confirmed(name: &str) -> ThreadResult {
+        ThreadResult::new(entry(name), Ok(output(Some(Stage::Confirmed), false)))
+    }
+
+    fn names(db: &DB) -> Vec<String> {
+        db.entries.iter().map(|e| e.username.to_owned()).collect()
+    }
+
+    /// Feed the results to `commit_results` and collect the entries of every
+    /// commit.
+    fn commits(results: Vec<ThreadResult>, batched: bool) -> Vec<Vec<String>> {
+        let (tx, rx) = channel();
+        for result in results {
+            tx.send(result).unwrap();
+        }
+        drop(tx);
+        let mut commits = Vec::new();
+        commit_results(rx, batched, |db| {
+            commits.push(names(db));
+            Ok::<_, String>(db.entries.iter().map(|e| e.uuid.to_owned()).collect())
+        });
+        commits
+    }
+
+    #[test]
+    fn unbatched_results_are_committed_one_by_one() {
+        let failed = ThreadResult::new(entry("bob"), Ok(output(None, false)));
+        assert_eq!(
+            commits(vec![confirmed("alice"), failed, confirmed("carol")], false),
+            vec![vec!["alice"], vec!["carol"]]
+        );
+    }
+
+    #[test]
+    fn batched_results_are_committed_together() {
+        let results = (0..BATCH_SIZE + 2)
+            .map(|i| confirmed(&format!("user{}", i)))
+            .collect();
+        let commits = commits(results, true);
+        assert_eq!(commits.len(), 2);
+        assert_eq!(commits[0].len(), BATCH_SIZE);
+        assert_eq!(commits[1], vec!["user20", "user21"]);
+    }
+
+    #[test]
+    fn batch_is_committed_once_no_result_arrives() {
+        let (tx, rx) = channel();
+        let commits = Arc::new(Mutex::new(Vec::new()));
+        let commits_ = Arc::clone(&commits);
+        let committer = thread::spawn(move || {
+            commit_results(rx, true, |db| {
+                commits_.lock().unwrap().push(names(db));
+                Ok::<_, String>(Vec::new())
+            })
+        });
+        tx.send(confirmed("alice")).unwrap();
+        thread::sleep(BATCH_WAIT + Duration::from_millis(500));
+        assert_eq!(*commits.lock().unwrap(), vec![vec!["alice"]]);
+        tx.send(confirmed("bob")).unwrap();
+        drop(tx);
+        committer.join().unwrap();
+        assert_eq!(*commits.lock().unwrap(), vec![vec!["alice"], vec!["bob"]]);
+    }
+
+    #[test]
+    fn entries_not_written_are_left_to_the_user() {
+        let mut pending = vec![entry("alice"), entry("bob")];
+        let mut committed = Vec::new();
+        commit_entries(&mut pending, &mut |db: &DB| {
+            committed.push(names(db));
+            Ok::<_, String>(vec![Uuid::Pass("alice".to_owned())])
+        });
+        assert!(pending.is_empty());
+        assert_eq!(committed, vec![vec!["alice", "bob"]]);
+
+        let mut pending = vec![entry("carol")];
+        commit_entries(&mut pending, &mut |_: &DB| Err("database locked"));
+        assert!(pending.is_empty());
+
+        assert_eq!(
+            not_written_error(&entry("bob")),
+            "Error: The new password for website \'bob.com\' with username: \'bob\' was not written, new password: new-bob"
+        );
+    }
+
+    fn pgid(output: &Output) -> u32 {
+        str::from_utf8(&output.stdout)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn finished_commands_are_not_killed_on_exit() {
+        let (output, timed_out) =
+            cmd_timeout("sh", &["-c", "echo $$"], &[], Duration::from_secs(5)).unwrap();
+        assert!(!timed_out);
+        assert!(!PROCESS_GROUPS.lock().unwrap().contains(&pgid(&output)));
+    }
+
+    #[test]
+    fn timed_out_commands_are_not_killed_again_on_exit() {
+        let (output, timed_out) = cmd_timeout(
+            "sh",
+            &["-c", "echo $$; sleep 10 & wait"],
+            &[],
+            Duration::from_millis(300),
+        )
+        .unwrap();
+        assert!(timed_out);
+        assert!(!PROCESS_GROUPS.lock().unwrap().contains(&pgid(&output)));
+    }
+
+    #[test]
+    fn only_runs_interrupted_before_submitting_are_retried() {
+        assert!(output(Some(Stage::LoggedIn), true).retryable());
+        assert!(!output(None, true).retryable());
+        assert!(!output(Some(Stage::Submitted), true).retryable());
+        assert!(!output(Some(Stage::Confirmed), true).retryable());
+        assert!(!output(Some(Stage::LoggedIn), false).retryable());
+    }
+
+    #[test]
+    fn unconfirmed_timeout_prints_the_new_password() {
+        let db_entry = DBEntry::new(
+            "https://example.com".to_owned(),
+            "alice".to_owned(),
+            "old".to_owned(),
+            "n3w-password".to_owned(),
+        );
+        let err = script_error(db_entry, output(None, true));
+        assert!(matches!(err, Error::ScriptUnconfirmedError { .. }));
+        assert!(err.to_string().contains("n3w-password"));
+    }
+
+    #[test]
+    fn verification_overrules_the_report() {
+        let mut run = output(None, true);
+        run.verification = Some(Verification::NewPassword);
+        assert!(run.confirmed());
+        run.verification = Some(Verification::OldPassword);
+        assert_eq!(run.outcome(), Outcome::Failed);
+    }
 }
@@ -0,0 +1,591 @@
+use std::net::TcpListener;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use std::{io, mem, result};
+
+use serde_json::{json, Value};
+use snafu::{ResultExt, Snafu};
+use url::Url;
+
+use crate::config::BrowserType;
+use crate::utils::{track_process_group, untrack_process_group};
+
+pub const GECKODRIVER_BIN: &str = "geckodriver";
+pub const CHROMEDRIVER_BIN: &str = "chromedriver";
+const LOCALHOST: &str = "127.0.0.1";
+const ELEMENT_KEY: &str = "element-6066-11e4-a52e-4f735466cecf";
+const DRIVER_START_TIMEOUT: Duration = Duration::from_secs(20);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+pub const ELEMENT_TIMEOUT: Duration = Duration::from_secs(30);
+const BLANK_PAGE: &str = "about:blank";
+const CLEAR_STORAGE_SCRIPT: &str = "localStorage.clear(); sessionStorage.clear();";
+
+#[derive(Debug, Snafu)]
+pub enum LibraryError {
+    IoError { source: io::Error },
+    HttpError { source: Box<ureq::Error> },
+}
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not start {}: {}", driver, source))]
+    DriverStartError {
+        driver: String,
+        source: LibraryError,
+    },
+    #[snafu(display("{} did not become ready", driver))]
+    DriverNotReady {
+        driver: String,
+    },
+    #[snafu(display("WebDriver request {} failed: {}", command, source))]
+    RequestError {
+        command: String,
+        source: LibraryError,
+    },
+    #[snafu(display("WebDriver command {} failed with {}: {}", command, error, message))]
+    CommandError {
+        command: String,
+        error: String,
+        message: String,
+    },
+    #[snafu(display("WebDriver returned an unexpected response for {}", command))]
+    ResponseError {
+        command: String,
+    },
+    #[snafu(display("Element \'{}\' was not found", selector))]
+    ElementNotFound {
+        selector: String,
+    },
+    #[snafu(display("Element \'{}\' has an unexpected text: {}", selector, text))]
+    TextMismatch {
+        selector: String,
+        text: String,
+    },
+}
+
+type Result<T, E = Error> = result::Result<T, E>;
+
+/// A geckodriver or chromedriver process listening on a free local port.
+#[derive(Debug)]
+pub struct Driver {
+    process: Child,
+    url: String,
+    browser_type: BrowserType,
+}
+
+impl Driver {
+    /// Start the driver binary at `driver`, a path or a name in the PATH.
+    pub fn start(browser_type: &BrowserType, driver: &str) -> Result<Self> {
+        let port = free_port()
+            .context(IoError)
+            .context(DriverStartError { driver })?;
+        let port_arg = match browser_type {
+            BrowserType::Firefox => vec!["--port".to_owned(), port.to_string()],
+            BrowserType::Chrome => vec![format!("--port={}", port)],
+        };
+        let process = Command::new(driver)
+            .args(&port_arg)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            // Keep Ctrl-C from killing the browsers of running recipes
+            .process_group(0)
+            .spawn()
+            .context(IoError)
+            .context(DriverStartError { driver })?;
+        track_process_group(process.id());
+
+        let driver_ = Self {
+            process,
+            url: format!("http://{}:{}", LOCALHOST, port),
+            browser_type: browser_type.clone(),
+        };
+        let start = Instant::now();
+        while start.elapsed() < DRIVER_START_TIMEOUT {
+            let ready = request(&driver_.url, "GET", "/status", None)
+                .map(|value| value["ready"].as_bool().unwrap_or(false))
+                .unwrap_or(false);
+            if ready {
+                return Ok(driver_);
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+        Err(Error::DriverNotReady {
+            driver: driver.to_owned(),
+        })
+    }
+
+    pub fn new_session(&self, browser_args: &[String]) -> Result<Session> {
+        let capabilities = match self.browser_type {
+            BrowserType::Firefox => json!({
+                "browserName": "firefox",
+                "moz:firefoxOptions": { "args": browser_args },
+            }),
+            BrowserType::Chrome => json!({
+                "browserName": "chrome",
+                "goog:chromeOptions": { "args": browser_args },
+            }),
+        };
+        let value = request(
+            &self.url,
+            "POST",
+            "/session",
+            Some(json!({ "capabilities": { "alwaysMatch": capabilities } })),
+        )?;
+        let id = value["sessionId"]
+            .as_str()
+            .ok_or(Error::ResponseError {
+                command: "/session".to_owned(),
+            })?
+            .to_owned();
+        Ok(Session {
+            url: format!("{}/session/{}", self.url, id),
+            origins: Mutex::new(Vec::new()),
+        })
+    }
+}
+
+impl Drop for Driver {
+    fn drop(&mut self) {
+        untrack_process_group(self.process.id());
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+    }
+}
+
+pub fn driver_bin(browser_type: &BrowserType) -> &'static str {
+    match browser_type {
+        BrowserType::Firefox => GECKODRIVER_BIN,
+        BrowserType::Chrome => CHROMEDRIVER_BIN,
+    }
+}
+
+/// Let the OS pick a free port instead of guessing one.
+pub fn free_port() -> io::Result<u16> {
+    Ok(TcpListener::bind((LOCALHOST, 0))?.local_addr()?.port())
+}
+
+fn request(base_url: &str, method: &str, command: &str, body: Option<Value>) -> Result<Value> {
+    let agent = ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build();
+    let request = agent.request(method, &format!("{}{}", base_url, command));
+    let response = match body {
+        Some(body) => request.send_json(body),
+        None => request.call(),
+    };
+    // WebDriver reports errors with an error status and a JSON body
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(err) => {
+            return Err(Error::RequestError {
+                command: command.to_owned(),
+                source: LibraryError::HttpError {
+                    source: Box::new(err),
+                },
+            })
+        }
+    };
+
+    let json: Value = response
+        .into_json()
+        .context(IoError)
+        .context(RequestError { command })?;
+    let value = json.get("value").cloned().unwrap_or(Value::Null);
+    if let Some(error) = value.get("error").map(|e| e.as_str()).flatten() {
+        return Err(Error::CommandError {
+            command: command.to_owned(),
+            error: error.to_owned(),
+            message: value["message"].as_str().unwrap_or("").to_owned(),
+        });
+    }
+    Ok(value)
+}
+
+/// A browser session of a driver. The origins of all pages it showed are
+/// remembered, so their cookies and storage can be deleted on a reset.
+#[derive(Debug)]
+pub struct Session {
+    url: String,
+    origins: Mutex<Vec<String>>,
+}
+
+impl Session {
+    fn command(&self, method: &str, command: &str, body: Option<Value>) -> Result<Value> {
+        request(&self.url, method, command, body)
+    }
+
+    fn navigate(&self, url: &str) -> Result<()> {
+        self.command("POST", "/url", Some(json!({ "url": url })))
+            .map(|_| ())
+    }
+
+    fn add_origin(&self, url: &str) {
+        if let Some(origin) = origin(url) {
+            let mut origins = self.origins.lock().unwrap();
+            if !origins.contains(&origin) {
+                origins.push(origin);
+            }
+        }
+    }
+
+    /// Remember the origin of the current page, e.g. after a redirect.
+    fn add_current_origin(&self) {
+        if let Ok(url) = self.command("GET", "/url", None) {
+            self.add_origin(url.as_str().unwrap_or(""));
+        }
+    }
+
+    pub fn goto(&self, url: &str) -> Result<()> {
+        self.add_origin(url);
+        let result = self.navigate(url);
+        self.add_current_origin();
+        result
+    }
+
+    /// Find the first element matching the selector, waiting up to `timeout`
+    /// for it to appear.
+    pub fn find(&self, using: &str, selector: &str, timeout: Duration) -> Result<String> {
+        let start = Instant::now();
+        loop {
+            let result = self.command(
+                "POST",
+                "/element",
+                Some(json!({ "using": using, "value": selector })),
+            );
+            match result {
+                Ok(value) => {
+                    return value[ELEMENT_KEY].as_str().map(|id| id.to_owned()).ok_or(
+                        Error::ResponseError {
+                            command: "/element".to_owned(),
+                        },
+                    )
+                }
+                Err(Error::CommandError { .. }) if start.elapsed() < timeout => {
+                    thread::sleep(POLL_INTERVAL)
+                }
+                Err(Error::CommandError { .. }) => {
+                    return Err(Error::ElementNotFound {
+                        selector: selector.to_owned(),
+                    })
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    pub fn click(&self, element: &str) -> Result<()> {
+        let result = self
+            .command(
+                "POST",
+                &format!("/element/{}/click", element),
+                Some(json!({})),
+            )
+            .map(|_| ());
+        self.add_current_origin();
+        result
+    }
+
+    pub fn fill(&self, element: &str, text: &str) -> Result<()> {
+        self.command(
+            "POST",
+            &format!("/element/{}/clear", element),
+            Some(json!({})),
+        )?;
+        self.command(
+            "POST",
+            &format!("/element/{}/value", element),
+            Some(json!({ "text": text })),
+        )
+        .map(|_| ())
+    }
+
+    pub fn text(&self, element: &str) -> Result<String> {
+        let value = self.command("GET", &format!("/element/{}/text", element), None)?;
+        Ok(value.as_str().unwrap_or("").to_owned())
+    }
+
+    /// Delete the cookies and web storage of every origin the session showed
+    /// and leave it on a blank page, so the next entry, possibly another
+    /// account of the same site, does not start logged in.
+    pub fn reset(&self) -> Result<()> {
+        let origins = mem::take(&mut *self.origins.lock().unwrap());
+        for origin in origins {
+            self.navigate(&origin)?;
+            self.command("DELETE", "/cookie", None)?;
+            self.command(
+                "POST",
+                "/execute/sync",
+                Some(json!({ "script": CLEAR_STORAGE_SCRIPT, "args": [] })),
+            )?;
+        }
+        self.navigate(BLANK_PAGE)
+    }
+
+    pub fn quit(&self) {
+        let _ = self.command("DELETE", "", None);
+    }
+}
+
+/// A driver process with the session of one worker, which is kept alive
+/// across entries.
+#[derive(Debug)]
+pub struct Slot {
+    driver: Driver,
+    session: Option<Session>,
+}
+
+impl Slot {
+    pub fn session(&self) -> &Session {
+        self.session
+            .as_ref()
+            .expect("SessionPool::get returns slots with a session")
+    }
+}
+
+/// Keeps up to `size` sessions alive across entries. geckodriver only
+/// allows one session per process, so every session gets its own driver.
+/// Drivers and sessions are started when they are first needed, a session is
+/// reset when it is put back and replaced if that fails.
+#[derive(Debug)]
+pub struct SessionPool {
+    browser_type: BrowserType,
+    driver: String,
+    browser_args: Vec<String>,
+    size: usize,
+    slots: Mutex<Slots>,
+    available: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Slots {
+    idle: Vec<Slot>,
+    started: usize,
+}
+
+impl SessionPool {
+    pub fn new(
+        browser_type: &BrowserType,
+        driver: &str,
+        browser_args: Vec<String>,
+        size: usize,
+    ) -> Self {
+        Self {
+            browser_type: browser_type.clone(),
+            driver: driver.to_owned(),
+            browser_args,
+            size: size.max(1),
+            slots: Mutex::new(Slots::default()),
+            available: Condvar::new(),
+        }
+    }
+
+    /// Take an idle slot or, as long as fewer than `size` exist, start a new
+    /// driver. Waits until a slot is put back otherwise.
+    pub fn get(&self) -> Result<Slot> {
+        let mut slots = self.slots.lock().unwrap();
+        let slot = loop {
+            if let Some(slot) = slots.idle.pop() {
+                break Some(slot);
+            }
+            if slots.started < self.size {
+                slots.started += 1;
+                break None;
+            }
+            slots = self.available.wait(slots).unwrap();
+        };
+        drop(slots);
+
+        let mut slot = match slot {
+            Some(slot) => slot,
+            None => match Driver::start(&self.browser_type, &self.driver) {
+                Ok(driver) => Slot {
+                    driver,
+                    session: None,
+                },
+                Err(err) => {
+                    self.slots.lock().unwrap().started -= 1;
+                    self.available.notify_one();
+                    return Err(err);
+                }
+            },
+        };
+        if slot.session.is_none() {
+            match slot.driver.new_session(&self.browser_args) {
+                Ok(session) => slot.session = Some(session),
+                Err(err) => {
+                    self.put(slot);
+                    return Err(err);
+                }
+            }
+        }
+        Ok(slot)
+    }
+
+    /// Reset the session of the slot for the next entry and make the slot
+    /// available again. A session which could not be reset is closed, the
+    /// next entry starts a new one.
+    pub fn put(&self, mut slot: Slot) {
+        if let Some(session) = slot.session.take() {
+            match session.reset() {
+                Ok(_) => slot.session = Some(session),
+                Err(_) => session.quit(),
+            }
+        }
+        self.slots.lock().unwrap().idle.push(slot);
+        self.available.notify_one();
+    }
+}
+
+impl Drop for SessionPool {
+    fn drop(&mut self) {
+        for slot in &self.slots.lock().unwrap().idle {
+            if let Some(session) = &slot.session {
+                session.quit();
+            }
+        }
+    }
+}
+
+/// The origin of a URL, e.g. `https://github.com`, pages without one like
+/// `about:blank` have none.
+fn origin(url: &str) -> Option<String> {
+    let origin = Url::parse(url).ok()?.origin();
+    if origin.is_tuple() {
+        Some(origin.ascii_serialization())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+
+    use super::*;
+
+    /// Read one request and return its method, path and JSON body.
+    fn read_request(stream: &TcpStream) -> Option<(String, String, Value)> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_owned();
+        let path = parts.next()?.to_owned();
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':')?;
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().ok()?;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        Some((method, path, body))
+    }
+
+    /// A WebDriver session which follows `POST /url`, answers `GET /url` and
+    /// records all commands as "<method> <command> <url or script>".
+    fn mock_session() -> (Session, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind((LOCALHOST, 0)).unwrap();
+        let url = format!("http://{}/session/1", listener.local_addr().unwrap());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let log_ = log.clone();
+        thread::spawn(move || {
+            let mut current = BLANK_PAGE.to_owned();
+            for mut stream in listener.incoming().filter_map(|s| s.ok()) {
+                let (method, path, body) = match read_request(&stream) {
+                    Some(request) => request,
+                    None => continue,
+                };
+                let command = path.trim_start_matches("/session/1");
+                let value = match (method.as_str(), command) {
+                    ("GET", "/url") => json!(current),
+                    ("POST", "/url") => {
+                        current = body["url"].as_str().unwrap_or("").to_owned();
+                        log_.lock()
+                            .unwrap()
+                            .push(format!("{} {} {}", method, command, current));
+                        Value::Null
+                    }
+                    _ => {
+                        let arg = body["script"].as_str().unwrap_or("");
+                        log_.lock()
+                            .unwrap()
+                            .push(format!("{} {} {}", method, command, arg));
+                        Value::Null
+                    }
+                };
+                let body = json!({ "value": value }).to_string();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+        let session = Session {
+            url,
+            origins: Mutex::new(Vec::new()),
+        };
+        (session, log)
+    }
+
+    #[test]
+    fn reset_clears_every_visited_origin() {
+        let (session, log) = mock_session();
+        session.goto("https://github.com/login").unwrap();
+        session.goto("https://github.com/settings").unwrap();
+        session.goto("https://sso.example.com/auth").unwrap();
+        log.lock().unwrap().clear();
+
+        session.reset().unwrap();
+        let clear = format!("POST /execute/sync {}", CLEAR_STORAGE_SCRIPT);
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![
+                "POST /url https://github.com".to_owned(),
+                "DELETE /cookie ".to_owned(),
+                clear.to_owned(),
+                "POST /url https://sso.example.com".to_owned(),
+                "DELETE /cookie ".to_owned(),
+                clear,
+                format!("POST /url {}", BLANK_PAGE),
+            ]
+        );
+
+        // The origins are forgotten once they are cleared
+        log.lock().unwrap().clear();
+        session.reset().unwrap();
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec![format!("POST /url {}", BLANK_PAGE)]
+        );
+    }
+
+    #[test]
+    fn origins_of_pages() {
+        assert_eq!(
+            origin("https://github.com/settings/security?x=1").as_deref(),
+            Some("https://github.com")
+        );
+        assert_eq!(
+            origin("http://127.0.0.1:8080/login").as_deref(),
+            Some("http://127.0.0.1:8080")
+        );
+        assert_eq!(origin(BLANK_PAGE), None);
+        assert_eq!(origin("data:text/html,hi"), None);
+    }
+}